@@ -0,0 +1,70 @@
+use std::io::{Cursor, Write};
+
+use actix_web::{
+    http::header::{ContentDisposition, DispositionParam, DispositionType, CONTENT_DISPOSITION},
+    HttpRequest, HttpResponse,
+};
+use serde::Serialize;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{config::Config, output};
+
+/// A ZIP of converted images, built in memory.
+pub struct Archive {
+    zip: ZipWriter<Cursor<Vec<u8>>>,
+    /// Encoded images are already compressed.
+    stored: SimpleFileOptions,
+}
+
+impl Archive {
+    pub fn new() -> Self {
+        Archive {
+            zip: ZipWriter::new(Cursor::new(Vec::new())),
+            stored: SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+        }
+    }
+
+    pub fn add_image(&mut self, name: &str, image: &[u8]) -> anyhow::Result<()> {
+        self.zip.start_file(name, self.stored)?;
+        self.zip.write_all(image)?;
+
+        Ok(())
+    }
+
+    /// Adds `manifest` as `manifest.json`.
+    pub fn add_manifest(&mut self, manifest: &impl Serialize) -> anyhow::Result<()> {
+        self.zip
+            .start_file("manifest.json", SimpleFileOptions::default())?;
+        serde_json::to_writer_pretty(&mut self.zip, manifest)?;
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> anyhow::Result<Vec<u8>> {
+        Ok(self.zip.finish()?.into_inner())
+    }
+}
+
+/// Sends a finished archive as a download named `filename`.
+pub fn respond(
+    zip: Vec<u8>,
+    filename: String,
+    config: &Config,
+    req: &HttpRequest,
+) -> actix_web::Result<HttpResponse> {
+    let mut res = output::respond(zip, "application/zip", config, req)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    res.headers_mut().insert(
+        CONTENT_DISPOSITION,
+        ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(filename)],
+        }
+        .to_string()
+        .parse()
+        .expect("a Content-Disposition is a valid header value"),
+    );
+
+    Ok(res)
+}
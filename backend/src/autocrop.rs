@@ -0,0 +1,80 @@
+use crate::Decoded;
+
+impl Decoded {
+    /// Trims uniform borders off the image. The background color is taken from the
+    /// top-left pixel, and any pixel whose channels are all within `tolerance` of it
+    /// counts as background. Images that are entirely background are left untouched.
+    ///
+    /// `tolerance` is on the 8-bit scale, and is scaled up for 16-bit images.
+    pub fn autocrop(&mut self, tolerance: u8) {
+        let channels = self.color_type.channels();
+        let bpp = self.bytes_per_pixel();
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let stride = width * bpp;
+
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        let wide = self.bit_depth == 16;
+        let tolerance = match wide {
+            true => tolerance as u16 * 257,
+            false => tolerance as u16,
+        };
+
+        let sample = |px: &[u8], c: usize| match wide {
+            true => u16::from_ne_bytes([px[2 * c], px[2 * c + 1]]),
+            false => px[c] as u16,
+        };
+
+        let background = &self.bytes[..bpp];
+
+        let is_background = |x: usize, y: usize| {
+            let px = &self.bytes[y * stride + x * bpp..][..bpp];
+
+            (0..channels).all(|c| sample(px, c).abs_diff(sample(background, c)) <= tolerance)
+        };
+
+        let row_is_background = |y: usize| (0..width).all(|x| is_background(x, y));
+
+        let Some(top) = (0..height).find(|&y| !row_is_background(y)) else {
+            return;
+        };
+
+        let bottom = (top..height).rfind(|&y| !row_is_background(y)).unwrap();
+
+        let column_is_background = |x: usize| (top..=bottom).all(|y| is_background(x, y));
+
+        let left = (0..width).find(|&x| !column_is_background(x)).unwrap();
+        let right = (left..width).rfind(|&x| !column_is_background(x)).unwrap();
+
+        let new_width = right - left + 1;
+        let new_height = bottom - top + 1;
+
+        if new_width == width && new_height == height {
+            return;
+        }
+
+        self.crop(left, top, new_width, new_height);
+    }
+
+    /// Cuts the image down to the `width`x`height` region with its top-left corner at
+    /// (`left`, `top`). The region must lie within the image.
+    pub fn crop(&mut self, left: usize, top: usize, width: usize, height: usize) {
+        let bpp = self.bytes_per_pixel();
+        let stride = self.width as usize * bpp;
+
+        let mut out = Vec::with_capacity(width * height * bpp);
+
+        for y in top..top + height {
+            let start = y * stride + left * bpp;
+
+            out.extend_from_slice(&self.bytes[start..start + width * bpp]);
+        }
+
+        self.bytes = out;
+        self.width = width as u32;
+        self.height = height as u32;
+    }
+}
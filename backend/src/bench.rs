@@ -0,0 +1,129 @@
+use std::{collections::BTreeMap, time::Instant};
+
+use actix_web::{http::header::AUTHORIZATION, web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+
+use crate::{config::Config, signing, ColorType, Decoded, EncodeOptions, Format, Metadata};
+
+/// Dimensions of the benchmark image, big enough for timings to mean something without
+/// tying up a worker for long.
+const BENCH_SIZE: (u32, u32) = (1024, 768);
+
+#[derive(Debug, Serialize)]
+struct Report {
+    width: u32,
+    height: u32,
+    formats: BTreeMap<String, FormatResult>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum FormatResult {
+    Ok { ms: f64, bytes: usize },
+    Failed { error: String },
+}
+
+/// Whether `req` carries `Authorization: Bearer <ADMIN_TOKEN>`. Always false when no
+/// token is configured.
+pub fn is_admin(req: &HttpRequest, config: &Config) -> bool {
+    let Some(token) = config.admin_token.as_deref() else {
+        return false;
+    };
+
+    let Some(given) = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+
+    signing::constant_time_eq(given.as_bytes(), token.as_bytes())
+}
+
+/// A deterministic photo-like test image: smooth gradients overlaid with fine detail, so
+/// encoders can't compress it away trivially.
+fn sample() -> Decoded {
+    let (width, height) = BENCH_SIZE;
+
+    let bytes = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .flat_map(|(x, y)| {
+            let noise = (x.wrapping_mul(2_654_435_761) ^ y.wrapping_mul(40_503)) >> 27;
+
+            [
+                (x * 255 / width) as u8 ^ noise as u8,
+                (y * 255 / height) as u8,
+                ((x + y) * 127 / (width + height)) as u8 + (noise * 4) as u8,
+            ]
+        })
+        .collect();
+
+    Decoded {
+        bytes,
+        color_type: ColorType::Rgb,
+        width,
+        height,
+        bit_depth: 8,
+        metadata: Metadata::default(),
+    }
+}
+
+/// Encodes a standard test image to every format at the instance's current settings,
+/// reporting how long each took and how big the output was. Admin-only, since each call
+/// costs several full encodes.
+pub async fn bench(req: HttpRequest, config: web::Data<Config>) -> impl Responder {
+    if config.admin_token.is_none() {
+        return Ok(HttpResponse::NotFound().body("Benchmarks are disabled"));
+    }
+
+    if !is_admin(&req, &config) {
+        return Ok(HttpResponse::Unauthorized().body("Missing or invalid admin token"));
+    }
+
+    let report = web::block(move || {
+        let image = sample();
+
+        let formats = [
+            Format::Avif,
+            Format::Png,
+            Format::Jpeg,
+            Format::WebP,
+            Format::Pnm,
+            Format::Tiff,
+        ]
+        .into_iter()
+        .map(|mut format| {
+            // A fallback would time a different speed from the configured one.
+            let options = EncodeOptions {
+                avif_fallback: false,
+                ..EncodeOptions::defaults(&config, format)
+            };
+
+            let start = Instant::now();
+
+            let result = match format.encode(&image, &options) {
+                Ok(out) => FormatResult::Ok {
+                    ms: start.elapsed().as_secs_f64() * 1000.,
+                    bytes: out.len(),
+                },
+                Err(e) => FormatResult::Failed {
+                    error: format!("{e:#}"),
+                },
+            };
+
+            (format.to_string(), result)
+        })
+        .collect();
+
+        Report {
+            width: image.width,
+            height: image.height,
+            formats,
+        }
+    })
+    .await?;
+
+    Ok::<_, actix_web::Error>(HttpResponse::Ok().json(report))
+}
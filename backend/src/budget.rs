@@ -0,0 +1,170 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::header::{RETRY_AFTER, X_FORWARDED_FOR},
+    web, HttpRequest, HttpResponse,
+};
+
+use crate::config::TrustedProxies;
+
+tokio::task_local! {
+    /// Encode time charged so far by the request being handled.
+    static SPENT: Cell<Duration>;
+}
+
+#[derive(Debug)]
+struct Usage {
+    window_start: Instant,
+    spent: Duration,
+}
+
+/// Per-client allowance of encode time (`CPU_BUDGET_MS`) over a fixed window
+/// (`CPU_BUDGET_WINDOW`). A client that has used up its budget gets 429s until the window
+/// rolls over. Disabled when the budget is zero.
+///
+/// Clients are told apart by peer address, or by `X-Forwarded-For` when the peer is one
+/// of `TRUSTED_PROXIES`.
+#[derive(Debug)]
+pub struct CpuBudget {
+    budget: Duration,
+    window: Duration,
+    trusted_proxies: TrustedProxies,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl CpuBudget {
+    pub fn new(budget: Duration, window: Duration, trusted_proxies: TrustedProxies) -> Self {
+        CpuBudget {
+            budget,
+            window,
+            trusted_proxies,
+            usage: Mutex::default(),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.budget.is_zero()
+    }
+
+    /// The client `req` is charged to, or `None` if budgets are disabled.
+    pub fn client(&self, req: &HttpRequest) -> Option<String> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let peer = req.peer_addr()?.ip();
+        let forwarded_for: Vec<&str> = req
+            .headers()
+            .get_all(X_FORWARDED_FOR)
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+
+        Some(
+            self.trusted_proxies
+                .client(peer, &forwarded_for.join(","))
+                .to_string(),
+        )
+    }
+
+    /// How long until `client` may make requests again, if it's out of budget.
+    fn retry_after(&self, client: &str) -> Option<Duration> {
+        let usage = self.usage.lock().expect("budget lock poisoned");
+        let usage = usage.get(client)?;
+
+        let elapsed = usage.window_start.elapsed();
+
+        (usage.spent >= self.budget && elapsed < self.window).then(|| self.window - elapsed)
+    }
+
+    pub fn charge(&self, client: String, spent: Duration) {
+        let mut usage = self.usage.lock().expect("budget lock poisoned");
+
+        usage.retain(|_, usage| usage.window_start.elapsed() < self.window);
+
+        usage
+            .entry(client)
+            .or_insert_with(|| Usage {
+                window_start: Instant::now(),
+                spent: Duration::ZERO,
+            })
+            .spent += spent;
+    }
+}
+
+/// Times its own lifetime and charges it to the current request's budget when dropped.
+/// Does nothing outside a request unless wrapped in `measure`.
+pub struct Charge(Instant);
+
+impl Charge {
+    pub fn start() -> Self {
+        Charge(Instant::now())
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        let _ = SPENT.try_with(|spent| spent.set(spent.get() + self.0.elapsed()));
+    }
+}
+
+/// Runs `f` outside of a request, e.g. on a blocking thread, returning the encode time it
+/// used alongside its result so it can be charged to whoever asked for it.
+pub fn measure<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    SPENT.sync_scope(Cell::new(Duration::ZERO), || {
+        let out = f();
+
+        (out, SPENT.with(Cell::get))
+    })
+}
+
+/// Rejects clients that are out of budget, and charges everyone else for the encode time
+/// their request used.
+pub fn guard<S, B>(
+    budget: &web::Data<CpuBudget>,
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<EitherBody<B>>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let budget = budget.clone();
+    let client = budget.client(req.request());
+
+    let call = match client.as_deref().and_then(|c| budget.retry_after(c)) {
+        Some(retry_after) => Err(req.into_response(
+            HttpResponse::TooManyRequests()
+                .insert_header((RETRY_AFTER, retry_after.as_secs().max(1)))
+                .body("CPU time budget exhausted, try again later"),
+        )),
+        None => Ok(srv.call(req)),
+    };
+
+    async move {
+        let response = match call {
+            Ok(response) => response,
+            Err(rejected) => return Ok(rejected.map_into_right_body()),
+        };
+
+        let (response, spent) = SPENT
+            .scope(Cell::new(Duration::ZERO), async {
+                let response = response.await;
+
+                (response, SPENT.with(Cell::get))
+            })
+            .await;
+
+        if let Some(client) = client {
+            budget.charge(client, spent);
+        }
+
+        response.map(ServiceResponse::map_into_left_body)
+    }
+}
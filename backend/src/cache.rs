@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::{http::header::HeaderName, web, HttpResponse, Responder};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// `HIT` or `MISS`, on conversions while the result cache is enabled.
+pub const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+/// Digest of an input's bytes together with the options it was converted with.
+pub type Key = [u8; 32];
+
+/// A finished conversion, with what its response headers need.
+#[derive(Debug, Clone)]
+pub struct Cached {
+    pub body: Vec<u8>,
+    pub has_alpha: bool,
+    pub alpha_flattened: bool,
+    /// How much smaller an optimize-in-place output is than the upload.
+    pub bytes_saved: Option<usize>,
+    /// How a `raw` output's buffer is laid out.
+    pub raw_layout: Option<crate::output::RawLayout>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    cached: Cached,
+    inserted: Instant,
+    /// Value of `Cache::clock` when the entry was last read or written.
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<Key, Entry>,
+    /// Sum of the bodies' sizes.
+    bytes: usize,
+    clock: u64,
+}
+
+/// In-memory LRU cache of conversion outputs, bounded by total body size (`CACHE_SIZE`)
+/// with entries expiring after `CACHE_TTL`. A capacity of 0 disables it.
+#[derive(Debug)]
+pub struct Cache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    enabled: bool,
+    entries: usize,
+    bytes: usize,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl Cache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Cache {
+            capacity,
+            ttl,
+            entries: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Hashes `input` followed by the `Debug` form of `options`, which should hold every
+    /// resolved setting that affects the output.
+    pub fn key(mut input: impl Read, options: &impl Debug) -> io::Result<Key> {
+        let mut hasher = Sha256::new();
+
+        io::copy(&mut input, &mut hasher)?;
+        hasher.update(format!("{options:?}"));
+
+        Ok(hasher.finalize().into())
+    }
+
+    pub fn get(&self, key: &Key) -> Option<Cached> {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        entries.clock += 1;
+
+        let clock = entries.clock;
+
+        let cached = match entries.map.get_mut(key) {
+            Some(entry) if entry.inserted.elapsed() < self.ttl => {
+                entry.last_used = clock;
+
+                Some(entry.cached.clone())
+            }
+            Some(_) => {
+                let expired = entries.map.remove(key).expect("entry was just found");
+                entries.bytes -= expired.cached.body.len();
+
+                None
+            }
+            None => None,
+        };
+
+        match cached {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        cached
+    }
+
+    /// Stores a result, evicting the least recently used entries to make room. Results
+    /// bigger than the whole cache aren't kept.
+    pub fn insert(&self, key: Key, cached: Cached) {
+        let size = cached.body.len();
+
+        if size > self.capacity {
+            return;
+        }
+
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+
+        if let Some(old) = entries.map.remove(&key) {
+            entries.bytes -= old.cached.body.len();
+        }
+
+        while entries.bytes + size > self.capacity {
+            let Some(&oldest) = entries
+                .map
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key)
+            else {
+                break;
+            };
+
+            let evicted = entries.map.remove(&oldest).expect("key came from the map");
+            entries.bytes -= evicted.cached.body.len();
+        }
+
+        entries.clock += 1;
+
+        let last_used = entries.clock;
+
+        entries.bytes += size;
+        entries.map.insert(
+            key,
+            Entry {
+                cached,
+                inserted: Instant::now(),
+                last_used,
+            },
+        );
+    }
+
+    fn stats(&self) -> Stats {
+        let entries = self.entries.lock().expect("cache lock poisoned");
+
+        Stats {
+            enabled: self.is_enabled(),
+            entries: entries.map.len(),
+            bytes: entries.bytes,
+            capacity: self.capacity,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Hit/miss counters and current size of the result cache.
+pub async fn stats(cache: web::Data<Cache>) -> impl Responder {
+    HttpResponse::Ok().json(cache.stats())
+}
@@ -0,0 +1,154 @@
+use actix_multipart::form::{json::Json, tempfile::TempFile, MultipartForm};
+use actix_web::{web, HttpResponse, Responder};
+use rgb::RGB8;
+use serde::Serialize;
+
+use crate::{config::Config, decode_upload};
+
+/// Number of colors returned when the request doesn't specify a count.
+const DEFAULT_COUNT: usize = 5;
+/// Upper bound on the requested count, to keep clustering cheap.
+const MAX_COUNT: usize = 32;
+/// Images are sampled down to roughly this many pixels before clustering.
+const SAMPLE_PIXELS: usize = 64 * 64;
+const MAX_ITERATIONS: usize = 16;
+
+#[derive(Debug, MultipartForm)]
+pub struct ColorsForm {
+    file: TempFile,
+    count: Option<Json<usize>>,
+}
+
+#[derive(Debug, Serialize)]
+struct DominantColor {
+    hex: String,
+    /// Share of the (opaque) image covered by this color, in percent.
+    coverage: f32,
+}
+
+pub async fn dominant_colors(
+    config: web::Data<Config>,
+    MultipartForm(ColorsForm {
+        file: input,
+        count,
+    }): MultipartForm<ColorsForm>,
+) -> actix_web::Result<impl Responder, actix_web::Error> {
+    let count = count.map_or(DEFAULT_COUNT, |c| *c);
+
+    if !(1..=MAX_COUNT).contains(&count) {
+        return Ok(
+            HttpResponse::BadRequest().body(format!("count must be between 1 and {MAX_COUNT}"))
+        );
+    }
+
+    let decoded = match decode_upload(input, &config) {
+        Ok(decoded) => decoded,
+        Err(res) => return Ok(res),
+    };
+
+    let pixels = decoded.rgba_pixels();
+    let step = (pixels.len() / SAMPLE_PIXELS).max(1);
+
+    // Fully transparent pixels carry no visible color, so they shouldn't pull clusters around.
+    let samples: Vec<RGB8> = pixels
+        .iter()
+        .step_by(step)
+        .filter(|px| px.a > 0)
+        .map(|px| px.rgb())
+        .collect();
+
+    Ok(HttpResponse::Ok().json(kmeans(&samples, count)))
+}
+
+fn distance(a: [f32; 3], b: RGB8) -> f32 {
+    let dr = a[0] - b.r as f32;
+    let dg = a[1] - b.g as f32;
+    let db = a[2] - b.b as f32;
+
+    dr * dr + dg * dg + db * db
+}
+
+/// Clusters `samples` into at most `k` colors, sorted by descending coverage.
+fn kmeans(samples: &[RGB8], k: usize) -> Vec<DominantColor> {
+    if samples.is_empty() {
+        return Vec::new();
+    }
+
+    // Seed deterministically with samples spread evenly across the luma range, so the same
+    // image always yields the same palette.
+    let mut sorted = samples.to_vec();
+    sorted.sort_by_key(|px| 299 * px.r as u32 + 587 * px.g as u32 + 114 * px.b as u32);
+
+    let k = k.min(sorted.len());
+
+    let mut centroids: Vec<[f32; 3]> = (0..k)
+        .map(|i| {
+            let px = sorted[(2 * i + 1) * sorted.len() / (2 * k)];
+            [px.r as f32, px.g as f32, px.b as f32]
+        })
+        .collect();
+
+    let mut assignments = vec![usize::MAX; samples.len()];
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut changed = false;
+
+        for (px, assignment) in samples.iter().zip(&mut assignments) {
+            let nearest = centroids
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| distance(**a, *px).total_cmp(&distance(**b, *px)))
+                .map(|(i, _)| i)
+                .unwrap();
+
+            if *assignment != nearest {
+                *assignment = nearest;
+                changed = true;
+            }
+        }
+
+        let mut sums = vec![[0f32; 3]; k];
+        let mut counts = vec![0usize; k];
+
+        for (px, &cluster) in samples.iter().zip(&assignments) {
+            sums[cluster][0] += px.r as f32;
+            sums[cluster][1] += px.g as f32;
+            sums[cluster][2] += px.b as f32;
+            counts[cluster] += 1;
+        }
+
+        for ((centroid, sum), &count) in centroids.iter_mut().zip(&sums).zip(&counts) {
+            if count > 0 {
+                *centroid = sum.map(|c| c / count as f32);
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    let mut counts = vec![0usize; k];
+
+    for &cluster in &assignments {
+        counts[cluster] += 1;
+    }
+
+    let mut colors: Vec<_> = centroids
+        .iter()
+        .zip(&counts)
+        .filter(|(_, &count)| count > 0)
+        .map(|(centroid, &count)| {
+            let [r, g, b] = centroid.map(|c| c.round() as u8);
+
+            DominantColor {
+                hex: format!("#{r:02x}{g:02x}{b:02x}"),
+                coverage: count as f32 / samples.len() as f32 * 100.,
+            }
+        })
+        .collect();
+
+    colors.sort_by(|a, b| b.coverage.total_cmp(&a.coverage));
+
+    colors
+}
@@ -0,0 +1,420 @@
+use std::{
+    env, fmt::Display, net::IpAddr, ops::RangeInclusive, path::PathBuf, str::FromStr, thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, ensure};
+use tokio::sync::Semaphore;
+
+use crate::Format;
+
+/// Seconds in-flight requests get to finish after a shutdown signal, by default.
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+/// Seconds an idle HTTP connection is kept open for, by default. Matches actix's own.
+const DEFAULT_KEEP_ALIVE: u64 = 5;
+/// Longest side allowed by the `web_ready` preset, by default.
+const DEFAULT_WEB_READY_MAX_DIMENSION: u32 = 2048;
+/// Longest side any resize may produce, by default.
+const DEFAULT_MAX_OUTPUT_DIMENSION: u32 = 16384;
+/// Most pixels a decoded input may have, by default.
+const DEFAULT_MAX_INPUT_PIXELS: u64 = 100_000_000;
+/// Largest accepted request body, in bytes, by default.
+const DEFAULT_MAX_UPLOAD_SIZE: usize = 25_000_000;
+/// Most parts a multipart upload may have, by default.
+const DEFAULT_MAX_MULTIPART_PARTS: usize = 256;
+/// Most bytes of non-file multipart fields buffered in memory, by default.
+const DEFAULT_MULTIPART_MEMORY_LIMIT: usize = 1_000_000;
+/// Encoded size, in bytes, above which responses are streamed from a temp file, by
+/// default.
+const DEFAULT_OUTPUT_SPILL_THRESHOLD: usize = 8_000_000;
+/// How long cached results stay valid, in seconds, by default.
+const DEFAULT_CACHE_TTL: u64 = 3600;
+/// How long finished jobs are kept for polling, in seconds, by default.
+const DEFAULT_JOB_TTL: u64 = 3600;
+/// Most jobs queued, running or awaiting download at once, by default.
+const DEFAULT_MAX_JOBS: usize = 100;
+/// Most bytes of finished job archives kept in memory at once, by default.
+const DEFAULT_MAX_JOB_STORAGE: usize = 500_000_000;
+/// Length of the window CPU budgets are measured over, in seconds, by default.
+const DEFAULT_CPU_BUDGET_WINDOW: u64 = 60;
+/// JPEG/AVIF quality used when `DEFAULT_QUALITY` isn't set.
+const DEFAULT_QUALITY: f32 = 95.;
+/// Highest quality the lossy encoders may be asked for, by default.
+const DEFAULT_MAX_QUALITY: f32 = 100.;
+
+/// Preset encoder effort, trading CPU time per request against output size (`PROFILE`):
+///
+/// - `fast`: AVIF at speed 10, PNG at zlib's fastest level.
+/// - `balanced` (the default): AVIF at speed 6, PNG at zlib's default level.
+/// - `smallest`: AVIF at speed 3, PNG at zlib's best level.
+///
+/// How much each saves depends on the images and the hardware; `/bench` times every
+/// encoder at the running instance's settings, so profiles can be compared there.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Profile {
+    Fast,
+    Balanced,
+    Smallest,
+}
+
+impl Profile {
+    /// ravif speed, unless overridden by `DEFAULT_AVIF_SPEED` or `DEFAULT_AVIF_EFFORT`.
+    pub fn avif_speed(&self) -> u8 {
+        match self {
+            Profile::Fast => 10,
+            Profile::Balanced => 6,
+            Profile::Smallest => 3,
+        }
+    }
+
+    pub fn png_compression(&self) -> png::Compression {
+        match self {
+            Profile::Fast => png::Compression::Fast,
+            Profile::Balanced => png::Compression::Default,
+            Profile::Smallest => png::Compression::Best,
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fast" => Ok(Profile::Fast),
+            "balanced" => Ok(Profile::Balanced),
+            "smallest" => Ok(Profile::Smallest),
+
+            _ => Err("expected fast, balanced or smallest".to_string()),
+        }
+    }
+}
+
+/// A set of formats named as in `output_type`, parsed from a comma-separated list.
+#[derive(Debug, Clone)]
+pub struct Formats(Vec<Format>);
+
+impl Formats {
+    /// Every format uploads can be decoded from.
+    pub fn inputs() -> Self {
+        Formats(Format::INPUTS.to_vec())
+    }
+
+    /// Every format conversions can produce.
+    pub fn outputs() -> Self {
+        Formats([&Format::INPUTS[..], &[Format::Raw]].concat())
+    }
+
+    pub fn contains(&self, format: Format) -> bool {
+        self.0.contains(&format)
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.0.iter().map(Format::name).collect()
+    }
+}
+
+impl FromStr for Formats {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| Format::from_name(name).ok_or(format!("unknown format {name:?}")))
+            .collect::<Result<_, _>>()
+            .map(Formats)
+    }
+}
+
+/// Reverse proxies whose `X-Forwarded-For` entries are believed, parsed from a
+/// comma-separated list of addresses.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpAddr>);
+
+impl TrustedProxies {
+    /// The address a request really came from: the peer, or while that's a trusted proxy,
+    /// the `X-Forwarded-For` hop it reports, working from the right. Hops further left
+    /// than the first untrusted one were written by the client and could be anything.
+    pub fn client(&self, peer: IpAddr, forwarded_for: &str) -> IpAddr {
+        let mut client = peer;
+
+        for hop in forwarded_for.rsplit(',') {
+            if !self.0.contains(&client) {
+                break;
+            }
+
+            match hop.trim().parse() {
+                Ok(hop) => client = hop,
+                Err(_) => break,
+            }
+        }
+
+        client
+    }
+}
+
+impl FromStr for TrustedProxies {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| {
+                addr.parse()
+                    .map_err(|_| format!("invalid address {addr:?}"))
+            })
+            .collect::<Result<_, _>>()
+            .map(TrustedProxies)
+    }
+}
+
+/// Instance-wide settings, read from the environment once at startup.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How long to wait for in-flight conversions to drain on SIGTERM/SIGINT before
+    /// workers are forcibly stopped (`SHUTDOWN_TIMEOUT`, in seconds).
+    pub shutdown_timeout: Duration,
+    /// Number of HTTP worker threads (`HTTP_WORKERS`), defaulting to the number of CPUs.
+    /// Each worker runs its conversions inline, while AVIF encodes additionally fan out
+    /// over rayon's global pool, which has a thread per CPU unless `RAYON_NUM_THREADS`
+    /// says otherwise. Fewer workers than CPUs leaves that pool room to work;
+    /// `deterministic` AVIF encodes stay on their worker's thread.
+    pub http_workers: usize,
+    /// How long idle connections are kept open, in seconds (`KEEP_ALIVE`). 0 closes
+    /// each connection after its response.
+    pub keep_alive: Duration,
+    /// Longest side images are scaled down to under the `web_ready` preset
+    /// (`WEB_READY_MAX_DIMENSION`).
+    pub web_ready_max_dimension: u32,
+    /// Longest side an upscale is allowed to produce, guarding against huge allocations
+    /// (`MAX_OUTPUT_DIMENSION`).
+    pub max_output_dimension: u32,
+    /// Largest accepted multipart upload, in bytes (`MAX_UPLOAD_SIZE`).
+    pub max_upload_size: usize,
+    /// Most parts (fields and files) a multipart upload may have (`MAX_MULTIPART_PARTS`).
+    pub max_multipart_parts: usize,
+    /// Most bytes of non-file multipart fields, which are held in memory
+    /// (`MULTIPART_MEMORY_LIMIT`).
+    pub multipart_memory_limit: usize,
+    /// Most pixels an input may decode to (`MAX_INPUT_PIXELS`). Compressed images can be
+    /// tiny on the wire but enormous in memory.
+    pub max_input_pixels: u64,
+    /// Encoded size, in bytes, above which the output is written to a temp file and
+    /// streamed back instead of sent from memory (`OUTPUT_SPILL_THRESHOLD`).
+    pub output_spill_threshold: usize,
+    /// Where uploads and spilled outputs are buffered (`TEMP_DIR`), defaulting to the
+    /// system temp dir. Files are removed as soon as their request finishes.
+    pub temp_dir: PathBuf,
+    /// Total size, in bytes, of conversion results kept in memory for identical repeat
+    /// requests (`CACHE_SIZE`). The cache is disabled at the default of 0.
+    pub cache_size: usize,
+    /// How long a cached result may be served for (`CACHE_TTL`, in seconds).
+    pub cache_ttl: Duration,
+    /// Most `/jobs` submissions converted at once (`JOB_CONCURRENCY`), defaulting to the
+    /// number of CPUs. Further jobs queue until one finishes.
+    pub job_concurrency: usize,
+    /// How long a finished job's status and results stay available (`JOB_TTL`, in
+    /// seconds).
+    pub job_ttl: Duration,
+    /// Most jobs queued, running or awaiting download at once (`MAX_JOBS`). Submissions
+    /// beyond it get 503s, since each holds its uploads and then its archive.
+    pub max_jobs: usize,
+    /// Most bytes of finished job archives held at once (`MAX_JOB_STORAGE`). Submissions
+    /// get 503s while it's reached.
+    pub max_job_storage: usize,
+    /// Encode time each client may use per window before getting 429s (`CPU_BUDGET_MS`).
+    /// Disabled at the default of 0.
+    pub cpu_budget: Duration,
+    /// The window CPU budgets reset over (`CPU_BUDGET_WINDOW`, in seconds).
+    pub cpu_budget_window: Duration,
+    /// Addresses of the reverse proxies in front of the server (`TRUSTED_PROXIES`), e.g.
+    /// `10.0.0.2,10.0.0.3`. Clients are told apart by peer address unless it's one of
+    /// these, in which case `X-Forwarded-For` is consulted.
+    pub trusted_proxies: TrustedProxies,
+    /// Quality (1-100) for lossy JPEG and AVIF encodes (`DEFAULT_QUALITY`).
+    pub default_quality: f32,
+    /// Ceiling that requested JPEG qualities are silently clamped to (`MAX_JPEG_QUALITY`).
+    pub max_jpeg_quality: f32,
+    /// Ceiling that requested AVIF qualities are silently clamped to (`MAX_AVIF_QUALITY`).
+    pub max_avif_quality: f32,
+    /// Encoder effort preset (`PROFILE`).
+    pub profile: Profile,
+    /// ravif speed for AVIF encodes (`DEFAULT_AVIF_SPEED`, or its alias
+    /// `DEFAULT_AVIF_EFFORT`), from 1 (slowest, smallest output) to 10 (fastest). Defaults
+    /// to the profile's.
+    pub default_avif_speed: u8,
+    /// Floor that requested AVIF speeds are silently raised to (`MIN_AVIF_SPEED`), capping
+    /// the effort one encode may take.
+    pub min_avif_speed: u8,
+    /// Whether every codec is round-tripped at startup, with results reported by
+    /// `/readyz` (`SELF_TEST`). Disable for faster boots.
+    pub self_test: bool,
+    /// Bearer token for admin-only endpoints such as `/bench` (`ADMIN_TOKEN`). They're
+    /// disabled while it's unset.
+    pub admin_token: Option<String>,
+    /// Secret that every request's `signature` is checked against (`SIGNING_SECRET`), for
+    /// handing out time-limited URLs. Requests are accepted unsigned while it's unset.
+    /// While set, multipart endpoints are refused, since signatures can't cover their
+    /// options; conversions go through `/convert_raw`.
+    pub signing_secret: Option<String>,
+    /// Formats uploads may be in (`ALLOWED_INPUT_FORMATS`), e.g. `jpeg,png,webp`. Others
+    /// get a 403. All are allowed by default.
+    pub allowed_input_formats: Formats,
+    /// Formats conversions may produce (`ALLOWED_OUTPUT_FORMATS`), e.g. leaving out `avif`
+    /// for its CPU cost. Others get a 403. All are allowed by default.
+    pub allowed_output_formats: Formats,
+}
+
+impl Config {
+    /// Loads the configuration, failing on any variable that is set but can't be parsed.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let temp_dir: PathBuf = var("TEMP_DIR", env::temp_dir())?;
+        let profile = var("PROFILE", Profile::Balanced)?;
+        let min_avif_speed = var_in("MIN_AVIF_SPEED", 1, 1..=10)?;
+        let default_avif_speed = var_in(
+            "DEFAULT_AVIF_SPEED",
+            profile.avif_speed().max(min_avif_speed),
+            min_avif_speed..=10,
+        )?;
+        let default_avif_effort = var_in(
+            "DEFAULT_AVIF_EFFORT",
+            default_avif_speed,
+            min_avif_speed..=10,
+        )?;
+
+        ensure!(
+            env::var_os("DEFAULT_AVIF_SPEED").is_none()
+                || default_avif_effort == default_avif_speed,
+            "DEFAULT_AVIF_EFFORT ({default_avif_effort}) and DEFAULT_AVIF_SPEED \
+             ({default_avif_speed}) disagree; set only one"
+        );
+
+        ensure!(
+            temp_dir.is_dir(),
+            "TEMP_DIR ({}) is not a directory",
+            temp_dir.display()
+        );
+
+        Ok(Config {
+            shutdown_timeout: Duration::from_secs(var(
+                "SHUTDOWN_TIMEOUT",
+                DEFAULT_SHUTDOWN_TIMEOUT,
+            )?),
+            http_workers: var_in(
+                "HTTP_WORKERS",
+                thread::available_parallelism().map_or(1, usize::from),
+                1..=usize::MAX,
+            )?,
+            keep_alive: Duration::from_secs(var("KEEP_ALIVE", DEFAULT_KEEP_ALIVE)?),
+            web_ready_max_dimension: var(
+                "WEB_READY_MAX_DIMENSION",
+                DEFAULT_WEB_READY_MAX_DIMENSION,
+            )?,
+            max_output_dimension: var("MAX_OUTPUT_DIMENSION", DEFAULT_MAX_OUTPUT_DIMENSION)?,
+            max_upload_size: var("MAX_UPLOAD_SIZE", DEFAULT_MAX_UPLOAD_SIZE)?,
+            max_multipart_parts: var_in(
+                "MAX_MULTIPART_PARTS",
+                DEFAULT_MAX_MULTIPART_PARTS,
+                1..=usize::MAX,
+            )?,
+            multipart_memory_limit: var("MULTIPART_MEMORY_LIMIT", DEFAULT_MULTIPART_MEMORY_LIMIT)?,
+            max_input_pixels: var("MAX_INPUT_PIXELS", DEFAULT_MAX_INPUT_PIXELS)?,
+            output_spill_threshold: var("OUTPUT_SPILL_THRESHOLD", DEFAULT_OUTPUT_SPILL_THRESHOLD)?,
+            temp_dir,
+            cache_size: var("CACHE_SIZE", 0)?,
+            cache_ttl: Duration::from_secs(var("CACHE_TTL", DEFAULT_CACHE_TTL)?),
+            job_concurrency: var_in(
+                "JOB_CONCURRENCY",
+                thread::available_parallelism().map_or(1, usize::from),
+                1..=Semaphore::MAX_PERMITS,
+            )?,
+            job_ttl: Duration::from_secs(var("JOB_TTL", DEFAULT_JOB_TTL)?),
+            max_jobs: var_in("MAX_JOBS", DEFAULT_MAX_JOBS, 1..=usize::MAX)?,
+            max_job_storage: var("MAX_JOB_STORAGE", DEFAULT_MAX_JOB_STORAGE)?,
+            cpu_budget: Duration::from_millis(var("CPU_BUDGET_MS", 0)?),
+            cpu_budget_window: Duration::from_secs(var(
+                "CPU_BUDGET_WINDOW",
+                DEFAULT_CPU_BUDGET_WINDOW,
+            )?),
+            trusted_proxies: var("TRUSTED_PROXIES", TrustedProxies::default())?,
+            default_quality: var_in("DEFAULT_QUALITY", DEFAULT_QUALITY, 1. ..=100.)?,
+            max_jpeg_quality: var_in("MAX_JPEG_QUALITY", DEFAULT_MAX_QUALITY, 1. ..=100.)?,
+            max_avif_quality: var_in("MAX_AVIF_QUALITY", DEFAULT_MAX_QUALITY, 1. ..=100.)?,
+            profile,
+            default_avif_speed: default_avif_effort,
+            min_avif_speed,
+            self_test: var("SELF_TEST", true)?,
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+            signing_secret: env::var("SIGNING_SECRET").ok().filter(|s| !s.is_empty()),
+            allowed_input_formats: var("ALLOWED_INPUT_FORMATS", Formats::inputs())?,
+            allowed_output_formats: var("ALLOWED_OUTPUT_FORMATS", Formats::outputs())?,
+        })
+    }
+
+    /// The ceiling requested qualities are clamped to, for formats that take one.
+    pub fn max_quality(&self, format: &Format) -> Option<f32> {
+        match format {
+            Format::Jpeg => Some(self.max_jpeg_quality),
+            Format::Avif => Some(self.max_avif_quality),
+            _ => None,
+        }
+    }
+}
+
+/// Parses the environment variable `name`, falling back to `default` when it's unset.
+fn var<T>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|e| anyhow!("Invalid value for {name} ({value:?}): {e}")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Like `var`, but also rejects values outside `range`.
+fn var_in<T>(name: &str, default: T, range: RangeInclusive<T>) -> anyhow::Result<T>
+where
+    T: FromStr + PartialOrd + Display,
+    T::Err: Display,
+{
+    let value = var(name, default)?;
+
+    ensure!(
+        range.contains(&value),
+        "{name} must be between {} and {}, got {value}",
+        range.start(),
+        range.end()
+    );
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarded_for_is_only_believed_from_trusted_proxies() {
+        let proxies: TrustedProxies = "10.0.0.1, 10.0.0.2".parse().unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        // Straight from the client, whatever it claims.
+        assert_eq!(
+            proxies.client(ip("203.0.113.9"), "198.51.100.1"),
+            ip("203.0.113.9")
+        );
+        // Through both proxies, ignoring the spoofed entry the client sent first.
+        assert_eq!(
+            proxies.client(ip("10.0.0.2"), "1.2.3.4, 203.0.113.9, 10.0.0.1"),
+            ip("203.0.113.9")
+        );
+        // A proxy that forwarded no header is the best there is.
+        assert_eq!(proxies.client(ip("10.0.0.1"), ""), ip("10.0.0.1"));
+    }
+}
@@ -0,0 +1,295 @@
+use rgb::RGBA8;
+
+use crate::{ColorType, Decoded, Format};
+
+impl ColorType {
+    /// Parses the names accepted by `output_color_type`.
+    pub fn from_name(name: &str) -> Option<ColorType> {
+        match name {
+            "rgb" => Some(ColorType::Rgb),
+            "rgba" => Some(ColorType::Rgba),
+            "grayscale" => Some(ColorType::Grayscale),
+            "grayscale_alpha" => Some(ColorType::GrayscaleAlpha),
+
+            _ => None,
+        }
+    }
+
+    /// The name used for this layout in `output_color_type` and raw output headers.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorType::Rgb => "rgb",
+            ColorType::Rgba => "rgba",
+            ColorType::Grayscale => "grayscale",
+            ColorType::GrayscaleAlpha => "grayscale_alpha",
+            ColorType::YCbCr => "ycbcr",
+            ColorType::Cmyk => "cmyk",
+        }
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        matches!(self, ColorType::Rgba | ColorType::GrayscaleAlpha)
+    }
+}
+
+impl Format {
+    /// Whether the encoder for this format can write `color_type` as-is.
+    pub fn supports_color_type(&self, color_type: ColorType) -> bool {
+        use ColorType::*;
+
+        match self {
+            // ravif always encodes color, only dropping alpha when it's fully opaque.
+            Format::Avif => matches!(color_type, Rgb | Rgba),
+            Format::Png | Format::WebP => {
+                matches!(color_type, Grayscale | GrayscaleAlpha | Rgb | Rgba)
+            }
+            Format::Tiff => matches!(color_type, Grayscale | Rgb | Rgba | Cmyk),
+            Format::Jpeg => matches!(color_type, Grayscale | Rgb | YCbCr | Cmyk),
+            Format::Pnm => matches!(color_type, Grayscale | Rgb),
+            Format::Raw => true,
+        }
+    }
+
+    /// Whether the encoder can store an alpha channel in some layout.
+    pub fn supports_alpha(&self) -> bool {
+        self.supports_color_type(ColorType::Rgba)
+            || self.supports_color_type(ColorType::GrayscaleAlpha)
+    }
+
+    /// Whether the encoder keeps 16-bit samples rather than scaling them down.
+    pub fn supports_16bit(&self) -> bool {
+        matches!(self, Format::Png | Format::Tiff | Format::Pnm | Format::Raw)
+    }
+
+    /// Whether encoding discards detail regardless of settings.
+    pub fn is_lossy(&self) -> bool {
+        matches!(self, Format::Jpeg | Format::Avif)
+    }
+}
+
+impl Decoded {
+    /// Whether any pixel is less than fully opaque.
+    pub fn is_translucent(&self) -> bool {
+        let channels = self.color_type.channels();
+
+        match (self.color_type.has_alpha(), self.bit_depth) {
+            (false, _) => false,
+            (true, 16) => self.bytes.chunks_exact(channels * 2).any(|px| {
+                u16::from_ne_bytes([px[channels * 2 - 2], px[channels * 2 - 1]]) != 65535
+            }),
+            (true, _) => self
+                .bytes
+                .chunks_exact(channels)
+                .any(|px| px[channels - 1] != 255),
+        }
+    }
+
+    /// Replaces the image with its alpha channel as grayscale, keeping the bit depth.
+    /// Returns false, leaving the image untouched, if it has no alpha channel.
+    pub fn extract_alpha(&mut self) -> bool {
+        if !self.color_type.has_alpha() {
+            return false;
+        }
+
+        let channels = self.color_type.channels();
+        let sample = self.bit_depth as usize / 8;
+
+        self.bytes = self
+            .bytes
+            .chunks_exact(channels * sample)
+            .flat_map(|px| px[(channels - 1) * sample..].iter().copied())
+            .collect();
+        self.color_type = ColorType::Grayscale;
+
+        true
+    }
+
+    /// Makes the layout encodable as `format`. JPEG's YCbCr and CMYK become RGB elsewhere,
+    /// gray+alpha widens to RGBA where only that's supported, and alpha is flattened onto
+    /// white where no alpha layout is.
+    pub fn adapt_layout_for(&mut self, format: &Format) {
+        if format.supports_color_type(self.color_type) {
+            return;
+        }
+
+        self.convert_to_rgb();
+
+        if !self.color_type.has_alpha() || format.supports_color_type(self.color_type) {
+            return;
+        }
+
+        self.convert_color_type(match self.color_type {
+            _ if format.supports_color_type(ColorType::Rgba) => ColorType::Rgba,
+            ColorType::GrayscaleAlpha if format.supports_color_type(ColorType::Grayscale) => {
+                ColorType::Grayscale
+            }
+            _ => ColorType::Rgb,
+        });
+    }
+
+    /// Composites the image onto `background`, whose own alpha is ignored, dropping the
+    /// alpha channel. Gray images only stay gray over a gray background. Bit depth is
+    /// preserved.
+    pub fn flatten_onto(&mut self, background: RGBA8) {
+        if !self.color_type.has_alpha() {
+            return;
+        }
+
+        let RGBA8 {
+            r, g, b, ..
+        } = background;
+
+        if self.color_type == ColorType::GrayscaleAlpha && !(r == g && g == b) {
+            self.convert_color_type(ColorType::Rgba);
+        }
+
+        let wide = self.bit_depth == 16;
+        let max: u32 = if wide { 65535 } else { 255 };
+        let scale = |c: u8| c as u32 * max / 255;
+
+        let (channels, target, background) = match self.color_type {
+            ColorType::GrayscaleAlpha => (2, ColorType::Grayscale, vec![scale(r)]),
+            _ => (4, ColorType::Rgb, vec![scale(r), scale(g), scale(b)]),
+        };
+
+        let samples: Vec<u32> = match wide {
+            true => self
+                .bytes
+                .chunks_exact(2)
+                .map(|s| u16::from_ne_bytes([s[0], s[1]]) as u32)
+                .collect(),
+            false => self.bytes.iter().map(|&s| s as u32).collect(),
+        };
+
+        let out = samples.chunks_exact(channels).flat_map(|px| {
+            let a = px[channels - 1];
+
+            px[..channels - 1]
+                .iter()
+                .zip(&background)
+                .map(move |(&c, &bg)| (c * a + bg * (max - a) + max / 2) / max)
+        });
+
+        self.bytes = match wide {
+            true => out.flat_map(|s| (s as u16).to_ne_bytes()).collect(),
+            false => out.map(|s| s as u8).collect(),
+        };
+        self.color_type = target;
+    }
+
+    /// Converts the buffer to `target`, desaturating with Rec. 601 luma when dropping
+    /// color and flattening onto white when dropping alpha. Bit depth is preserved.
+    pub fn convert_color_type(&mut self, target: ColorType) {
+        self.convert_to_rgb();
+
+        if self.color_type == target {
+            return;
+        }
+
+        let wide = self.bit_depth == 16;
+        let max: u32 = if wide { 65535 } else { 255 };
+
+        let samples: Vec<u32> = match wide {
+            true => self
+                .bytes
+                .chunks_exact(2)
+                .map(|s| u16::from_ne_bytes([s[0], s[1]]) as u32)
+                .collect(),
+            false => self.bytes.iter().map(|&s| s as u32).collect(),
+        };
+
+        let mut out =
+            Vec::with_capacity(samples.len() / self.color_type.channels() * target.channels());
+
+        for px in samples.chunks_exact(self.color_type.channels()) {
+            let [r, g, b, a] = match self.color_type {
+                ColorType::Grayscale => [px[0], px[0], px[0], max],
+                ColorType::GrayscaleAlpha => [px[0], px[0], px[0], px[1]],
+                ColorType::Rgb => [px[0], px[1], px[2], max],
+                ColorType::Rgba => [px[0], px[1], px[2], px[3]],
+                ColorType::YCbCr | ColorType::Cmyk => unreachable!("converted to RGB above"),
+            };
+
+            let flatten = |c: u32| (c * a + max * (max - a) + max / 2) / max;
+            let luma = |r: u32, g: u32, b: u32| (299 * r + 587 * g + 114 * b + 500) / 1000;
+
+            match target {
+                ColorType::Grayscale => out.push(luma(flatten(r), flatten(g), flatten(b))),
+                ColorType::GrayscaleAlpha => out.extend([luma(r, g, b), a]),
+                ColorType::Rgb => out.extend([flatten(r), flatten(g), flatten(b)]),
+                ColorType::Rgba => out.extend([r, g, b, a]),
+                ColorType::YCbCr | ColorType::Cmyk => unreachable!("not a valid output_color_type"),
+            }
+        }
+
+        self.bytes = match wide {
+            true => out.iter().flat_map(|&s| (s as u16).to_ne_bytes()).collect(),
+            false => out.iter().map(|&s| s as u8).collect(),
+        };
+        self.color_type = target;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{EncodeOptions, Metadata};
+
+    /// Opaque at the center of a 9x9 image, fading by 60 per pixel of distance.
+    fn radial_alpha(x: u32, y: u32) -> u8 {
+        let distance = (x as f32 - 4.).hypot(y as f32 - 4.);
+
+        255 - (distance * 60.).min(255.) as u8
+    }
+
+    #[test]
+    fn extracts_a_radial_alpha_ramp() {
+        let image = Decoded {
+            bytes: (0..9 * 9)
+                .flat_map(|i| [200, 100, 50, radial_alpha(i % 9, i / 9)])
+                .collect(),
+            color_type: ColorType::Rgba,
+            width: 9,
+            height: 9,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        };
+
+        let mut format = Format::Png;
+        let png = format.encode(&image, &EncodeOptions::quick(90.)).unwrap();
+        let mut decoded = format.decode(Cursor::new(png), u64::MAX).unwrap();
+
+        assert!(decoded.extract_alpha());
+        assert_eq!(decoded.color_type, ColorType::Grayscale);
+        assert_eq!((decoded.width, decoded.height), (9, 9));
+        assert_eq!(
+            decoded.bytes,
+            (0..9 * 9)
+                .map(|i| radial_alpha(i % 9, i / 9))
+                .collect::<Vec<_>>()
+        );
+
+        // The center row ramps up to opaque and back down.
+        assert_eq!(
+            decoded.bytes[4 * 9..5 * 9],
+            [15, 75, 135, 195, 255, 195, 135, 75, 15]
+        );
+    }
+
+    #[test]
+    fn leaves_opaque_images_alone() {
+        let mut image = Decoded {
+            bytes: vec![1, 2, 3],
+            color_type: ColorType::Rgb,
+            width: 1,
+            height: 1,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        };
+
+        assert!(!image.extract_alpha());
+        assert_eq!(image.bytes, [1, 2, 3]);
+    }
+}
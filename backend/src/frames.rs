@@ -0,0 +1,322 @@
+use std::io::{Cursor, Read};
+
+use actix_multipart::form::{json::Json, tempfile::TempFile, MultipartForm};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use anyhow::{bail, ensure, Context};
+use png::{BlendOp, DisposeOp};
+use serde::Serialize;
+
+use crate::{
+    archive::{self, Archive},
+    config::Config,
+    disallowed_input, disallowed_output, ensure_valid_dimensions, output_format, upload_format,
+    upload_stem, ColorType, Decoded, EncodeOptions, Error, Format, Metadata,
+};
+
+/// Most frames one animation may be split into, since each is a full encode.
+const MAX_FRAMES: u32 = 500;
+
+#[derive(Debug, MultipartForm)]
+pub struct FramesForm {
+    file: TempFile,
+    /// Still format every frame is encoded as.
+    output_type: Json<String>,
+}
+
+/// `manifest.json` in the returned archive.
+#[derive(Debug, Serialize)]
+struct Manifest {
+    /// How many times the animation plays, with 0 meaning forever.
+    loop_count: u32,
+    frames: Vec<FrameEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct FrameEntry {
+    file: String,
+    /// How long the frame is shown for, in milliseconds.
+    delay_ms: u32,
+}
+
+/// Splits an animated PNG or WebP into a ZIP of still frames, each composited onto the
+/// full canvas as it would be displayed.
+pub async fn frames(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    MultipartForm(FramesForm {
+        file: input,
+        output_type,
+    }): MultipartForm<FramesForm>,
+) -> actix_web::Result<impl Responder, actix_web::Error> {
+    let Some(mut format) = output_format(&output_type) else {
+        return Ok(HttpResponse::BadRequest().body("Unsupported output type"));
+    };
+
+    if !config.allowed_output_formats.contains(format) {
+        return Ok(disallowed_output(format));
+    }
+
+    let input_format = upload_format(&input);
+
+    let Some(input_format @ (Format::Png | Format::WebP)) = input_format else {
+        return Ok(HttpResponse::BadRequest()
+            .body("Only animated PNG and WebP images can be split into frames"));
+    };
+
+    if !config.allowed_input_formats.contains(input_format) {
+        return Ok(disallowed_input(input_format));
+    }
+
+    let options = EncodeOptions::defaults(&config, format);
+
+    let stem = upload_stem(&input);
+
+    let mut buf = Vec::new();
+    input.file.into_file().read_to_end(&mut buf)?;
+
+    let mut archive = Archive::new();
+    let mut entries = Vec::new();
+
+    let mut write_frame = |mut frame: Decoded, delay_ms: u32| -> anyhow::Result<()> {
+        frame.adapt_layout_for(&format);
+
+        let out = format.encode(&frame, &options)?;
+        let file = format!("{stem}-{:04}.{}", entries.len(), format.extension());
+
+        archive.add_image(&file, &out)?;
+
+        entries.push(FrameEntry {
+            file,
+            delay_ms,
+        });
+
+        Ok(())
+    };
+
+    let split = match input_format {
+        Format::Png => apng_frames(&buf, config.max_input_pixels, &mut write_frame),
+        _ => webp_frames(&buf, config.max_input_pixels, &mut write_frame),
+    };
+
+    let loop_count = match split {
+        Ok(loop_count) => loop_count,
+        Err(e) => return Ok(HttpResponse::UnprocessableEntity().body(format!("{e:#}"))),
+    };
+
+    let manifest = Manifest {
+        loop_count,
+        frames: entries,
+    };
+
+    archive
+        .add_manifest(&manifest)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    let zip = archive
+        .finish()
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    archive::respond(zip, format!("{stem}-frames.zip"), &config, &req)
+}
+
+/// Rejects animations with too many frames, or too many pixels across all of them.
+fn ensure_frames_within(
+    format: Format,
+    frames: u32,
+    width: u32,
+    height: u32,
+    max_pixels: u64,
+) -> anyhow::Result<()> {
+    ensure_valid_dimensions(format, width, height, max_pixels)?;
+
+    ensure!(
+        frames <= MAX_FRAMES,
+        "{format} animation has {frames} frames, over the limit of {MAX_FRAMES}"
+    );
+    ensure!(
+        frames as u64 * width as u64 * height as u64 <= max_pixels,
+        "{format} animation has {frames} {width}x{height} frames, over the limit of \
+         {max_pixels} pixels in total"
+    );
+
+    Ok(())
+}
+
+fn rgba_frame(bytes: Vec<u8>, width: u32, height: u32) -> Decoded {
+    Decoded {
+        bytes,
+        color_type: ColorType::Rgba,
+        width,
+        height,
+        bit_depth: 8,
+        metadata: Metadata::default(),
+    }
+}
+
+/// Feeds each composited frame of an APNG (or the single image of a plain PNG) to `f`,
+/// returning the loop count.
+fn apng_frames(
+    buf: &[u8],
+    max_pixels: u64,
+    f: &mut impl FnMut(Decoded, u32) -> anyhow::Result<()>,
+) -> anyhow::Result<u32> {
+    let mut decoder = png::Decoder::new(buf);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+
+    let mut reader = decoder
+        .read_info()
+        .context(Error::CouldNotReadInfo(Format::Png))?;
+
+    let info = reader.info();
+    let (width, height) = (info.width, info.height);
+    let (frames, loop_count) = info
+        .animation_control
+        .map_or((1, 0), |actl| (actl.num_frames, actl.num_plays));
+    // Without an fcTL ahead of the image data, the default image isn't part of the
+    // animation.
+    let skip_default = info.animation_control.is_some() && info.frame_control.is_none();
+
+    ensure_frames_within(Format::Png, frames, width, height, max_pixels)?;
+
+    let mut canvas = vec![0; width as usize * height as usize * 4];
+    let mut frame = vec![0; reader.output_buffer_size()];
+
+    for index in 0..frames + skip_default as u32 {
+        let output = reader
+            .next_frame(&mut frame)
+            .context(Error::NextFrameNotFound)?;
+
+        if index == 0 && skip_default {
+            continue;
+        }
+
+        let control = reader.info().frame_control.unwrap_or(png::FrameControl {
+            width,
+            height,
+            ..Default::default()
+        });
+
+        let channels = output.color_type.samples();
+        let (x0, y0) = (control.x_offset as usize, control.y_offset as usize);
+        let (frame_width, frame_height) = (output.width as usize, output.height as usize);
+
+        if x0 + frame_width > width as usize || y0 + frame_height > height as usize {
+            bail!("APNG frame {index} extends past the canvas");
+        }
+
+        let previous = (control.dispose_op == DisposeOp::Previous).then(|| canvas.clone());
+
+        for y in 0..frame_height {
+            let row = &frame[y * output.line_size..][..frame_width * channels];
+
+            for (x, px) in row.chunks_exact(channels).enumerate() {
+                let src = match channels {
+                    1 => [px[0], px[0], px[0], 255],
+                    2 => [px[0], px[0], px[0], px[1]],
+                    3 => [px[0], px[1], px[2], 255],
+                    _ => [px[0], px[1], px[2], px[3]],
+                };
+
+                let at = ((y0 + y) * width as usize + x0 + x) * 4;
+                let dst = &mut canvas[at..at + 4];
+
+                match control.blend_op {
+                    BlendOp::Source => dst.copy_from_slice(&src),
+                    BlendOp::Over => blend_over(dst, src),
+                }
+            }
+        }
+
+        let delay_ms = match control.delay_den {
+            0 => control.delay_num as u32 * 10,
+            den => control.delay_num as u32 * 1000 / den as u32,
+        };
+
+        f(rgba_frame(canvas.clone(), width, height), delay_ms)?;
+
+        match control.dispose_op {
+            DisposeOp::None => {}
+            DisposeOp::Background => {
+                for y in y0..y0 + frame_height {
+                    canvas[(y * width as usize + x0) * 4..][..frame_width * 4].fill(0);
+                }
+            }
+            DisposeOp::Previous => canvas = previous.expect("saved before drawing"),
+        }
+    }
+
+    Ok(loop_count)
+}
+
+/// Composites `src` over `dst`, both straight-alpha RGBA.
+fn blend_over(dst: &mut [u8], src: [u8; 4]) {
+    let src_alpha = src[3] as u32;
+    let dst_alpha = dst[3] as u32 * (255 - src_alpha) / 255;
+    let alpha = src_alpha + dst_alpha;
+
+    if alpha == 0 {
+        dst.fill(0);
+        return;
+    }
+
+    for c in 0..3 {
+        dst[c] = ((src[c] as u32 * src_alpha + dst[c] as u32 * dst_alpha) / alpha) as u8;
+    }
+
+    dst[3] = alpha as u8;
+}
+
+/// Feeds each frame of an animated WebP (or the single image of a still one) to `f`,
+/// returning the loop count.
+fn webp_frames(
+    buf: &[u8],
+    max_pixels: u64,
+    f: &mut impl FnMut(Decoded, u32) -> anyhow::Result<()>,
+) -> anyhow::Result<u32> {
+    let mut decoder = image_webp::WebPDecoder::new(Cursor::new(buf))
+        .context(Error::CouldNotReadInfo(Format::WebP))?;
+
+    let (width, height) = decoder.dimensions();
+    let frames = match decoder.is_animated() {
+        true => decoder.num_frames(),
+        false => 1,
+    };
+
+    ensure_frames_within(Format::WebP, frames, width, height, max_pixels)?;
+
+    let has_alpha = decoder.has_alpha();
+    let mut frame = vec![
+        0;
+        decoder
+            .output_buffer_size()
+            .context(Error::CouldNotReadInfo(Format::WebP))?
+    ];
+
+    let to_rgba = |frame: &[u8]| -> Vec<u8> {
+        match has_alpha {
+            true => frame.to_vec(),
+            false => frame
+                .chunks_exact(3)
+                .flat_map(|px| [px[0], px[1], px[2], 255])
+                .collect(),
+        }
+    };
+
+    if !decoder.is_animated() {
+        decoder.read_image(&mut frame)?;
+        f(rgba_frame(to_rgba(&frame), width, height), 0)?;
+
+        return Ok(0);
+    }
+
+    for _ in 0..frames {
+        let delay_ms = decoder.read_frame(&mut frame)?;
+
+        f(rgba_frame(to_rgba(&frame), width, height), delay_ms)?;
+    }
+
+    Ok(match decoder.loop_count() {
+        image_webp::LoopCount::Forever => 0,
+        image_webp::LoopCount::Times(n) => n.get() as u32,
+    })
+}
@@ -0,0 +1,144 @@
+use std::{collections::BTreeMap, io::Cursor};
+
+use actix_web::{web, HttpResponse, Responder};
+use anyhow::ensure;
+use serde::Serialize;
+
+use crate::{ColorType, Decoded, EncodeOptions, Format, Metadata};
+
+/// Side length of the image pushed through each codec.
+const SAMPLE_SIZE: u32 = 8;
+
+/// Result of the startup codec self-test, served by `/readyz`.
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    /// Per-format results, or `None` when the self-test was skipped.
+    codecs: Option<BTreeMap<String, CodecStatus>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum CodecStatus {
+    Ok,
+    Failed { error: String },
+}
+
+impl Readiness {
+    pub fn skipped() -> Self {
+        Readiness {
+            codecs: None,
+        }
+    }
+
+    /// Round-trips a small image through every format's encoder and decoder, logging any
+    /// that fail so a broken deployment shows up at boot rather than on the first request.
+    pub fn self_test() -> Self {
+        let codecs = [
+            Format::Avif,
+            Format::Png,
+            Format::Jpeg,
+            Format::WebP,
+            Format::Pnm,
+            Format::Tiff,
+        ]
+        .into_iter()
+        .map(|mut format| {
+            let status = match round_trip(&mut format, SAMPLE_SIZE, SAMPLE_SIZE) {
+                Ok(()) => CodecStatus::Ok,
+                Err(e) => {
+                    log::error!("{format} codec self-test failed: {e:#}");
+
+                    CodecStatus::Failed {
+                        error: format!("{e:#}"),
+                    }
+                }
+            };
+
+            (format.to_string(), status)
+        })
+        .collect();
+
+        Readiness {
+            codecs: Some(codecs),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.codecs
+            .iter()
+            .flat_map(|codecs| codecs.values())
+            .all(|status| matches!(status, CodecStatus::Ok))
+    }
+}
+
+fn round_trip(format: &mut Format, width: u32, height: u32) -> anyhow::Result<()> {
+    let sample = Decoded {
+        bytes: (0..width * height)
+            .flat_map(|i| [(i * 4) as u8, 255 - (i * 4) as u8, 128])
+            .collect(),
+        color_type: ColorType::Rgb,
+        width,
+        height,
+        bit_depth: 8,
+        metadata: Metadata::default(),
+    };
+
+    let encoded = format.encode(&sample, &EncodeOptions::quick(90.))?;
+    let decoded = format.decode(Cursor::new(encoded), u64::MAX)?;
+
+    ensure!(
+        (decoded.width, decoded.height) == (width, height),
+        "round trip of a {width}x{height} image produced a {}x{} image",
+        decoded.width,
+        decoded.height
+    );
+
+    Ok(())
+}
+
+pub async fn readyz(readiness: web::Data<Readiness>) -> impl Responder {
+    match readiness.is_ready() {
+        true => HttpResponse::Ok().json(&**readiness),
+        false => HttpResponse::ServiceUnavailable().json(&**readiness),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [Format; 6] = [
+        Format::Avif,
+        Format::Png,
+        Format::Jpeg,
+        Format::WebP,
+        Format::Pnm,
+        Format::Tiff,
+    ];
+
+    /// Several encoders have minimum-size quirks, e.g. ravif's tiling and WebP's blocks.
+    #[test]
+    fn single_pixel_rows_and_columns_round_trip() {
+        for mut format in FORMATS {
+            for (width, height) in [(1, 1), (1, 1000), (1000, 1)] {
+                round_trip(&mut format, width, height)
+                    .unwrap_or_else(|e| panic!("{format} at {width}x{height}: {e:#}"));
+            }
+        }
+    }
+
+    #[test]
+    fn zero_area_images_are_rejected() {
+        let error = Format::Pnm
+            .decode(Cursor::new(b"P5 0 0 255\n"), u64::MAX)
+            .unwrap_err();
+
+        assert!(
+            matches!(
+                error.downcast_ref(),
+                Some(crate::Error::Empty(Format::Pnm, 0, 0))
+            ),
+            "{error:#}"
+        );
+    }
+}
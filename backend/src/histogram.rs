@@ -0,0 +1,106 @@
+use actix_multipart::form::{json::Json, tempfile::TempFile, MultipartForm};
+use actix_web::{web, HttpResponse, Responder};
+use rgb::RGBA8;
+use serde::Serialize;
+
+use crate::{config::Config, decode_upload};
+
+const BINS: usize = 256;
+
+#[derive(Debug, MultipartForm)]
+pub struct HistogramForm {
+    file: TempFile,
+    /// Subset of `red`, `green`, `blue`, `alpha` and `luminance` to return. Defaults to
+    /// everything but `alpha`.
+    channels: Option<Json<Vec<String>>>,
+}
+
+/// Per-channel pixel counts, each with one bin per 8-bit value.
+#[derive(Debug, Default, Serialize)]
+pub struct Histograms {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub red: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub green: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blue: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alpha: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub luminance: Option<Vec<u32>>,
+}
+
+/// Rec. 601 luma of an 8-bit RGB triple.
+pub fn luma(r: u8, g: u8, b: u8) -> u8 {
+    ((299 * r as u32 + 587 * g as u32 + 114 * b as u32 + 500) / 1000) as u8
+}
+
+impl Histograms {
+    /// Builds histograms for every channel over the given pixels.
+    pub fn compute(pixels: &[RGBA8]) -> Self {
+        let mut red = vec![0; BINS];
+        let mut green = vec![0; BINS];
+        let mut blue = vec![0; BINS];
+        let mut alpha = vec![0; BINS];
+        let mut luminance = vec![0; BINS];
+
+        for px in pixels {
+            red[px.r as usize] += 1;
+            green[px.g as usize] += 1;
+            blue[px.b as usize] += 1;
+            alpha[px.a as usize] += 1;
+            luminance[luma(px.r, px.g, px.b) as usize] += 1;
+        }
+
+        Histograms {
+            red: Some(red),
+            green: Some(green),
+            blue: Some(blue),
+            alpha: Some(alpha),
+            luminance: Some(luminance),
+        }
+    }
+}
+
+pub async fn histogram(
+    config: web::Data<Config>,
+    MultipartForm(HistogramForm {
+        file: input,
+        channels,
+    }): MultipartForm<HistogramForm>,
+) -> actix_web::Result<impl Responder, actix_web::Error> {
+    let channels = channels.map_or_else(
+        || {
+            vec!["red", "green", "blue", "luminance"]
+                .into_iter()
+                .map(String::from)
+                .collect()
+        },
+        |c| c.into_inner(),
+    );
+
+    if let Some(c) = channels
+        .iter()
+        .find(|c| !["red", "green", "blue", "alpha", "luminance"].contains(&c.as_str()))
+    {
+        return Ok(HttpResponse::BadRequest().body(format!("Unknown channel: {c}")));
+    }
+
+    let decoded = match decode_upload(input, &config) {
+        Ok(decoded) => decoded,
+        Err(res) => return Ok(res),
+    };
+
+    let mut all = Histograms::compute(&decoded.rgba_pixels());
+    let wanted = |name: &str| channels.iter().any(|c| c == name);
+
+    let histograms = Histograms {
+        red: all.red.take().filter(|_| wanted("red")),
+        green: all.green.take().filter(|_| wanted("green")),
+        blue: all.blue.take().filter(|_| wanted("blue")),
+        alpha: all.alpha.take().filter(|_| wanted("alpha")),
+        luminance: all.luminance.take().filter(|_| wanted("luminance")),
+    };
+
+    Ok(HttpResponse::Ok().json(histograms))
+}
@@ -0,0 +1,383 @@
+use std::io::{BufRead, Cursor, Seek};
+
+use actix_multipart::form::{tempfile::TempFile, MultipartForm};
+use actix_web::{web, HttpResponse, Responder};
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{
+    config::Config,
+    disallowed_input,
+    orient::{boxes, find_box},
+    pnm, tiff_page_count, upload_format, Error, Format,
+};
+
+#[derive(Debug, MultipartForm)]
+pub struct InfoForm {
+    file: TempFile,
+}
+
+/// What `/info` reports about an upload, read from headers where the format allows it.
+#[derive(Debug, Serialize)]
+pub struct ImageInfo {
+    format: String,
+    width: u32,
+    height: u32,
+    /// Number of frames; 1 for still images.
+    frames: u32,
+    /// Number of separately convertible pages, selected with `page`. Only multi-page TIFFs
+    /// have more than 1.
+    pages: u32,
+    /// Length of one pass through the animation in milliseconds. Absent for stills.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    /// How many times the animation plays, with 0 meaning forever. Absent for stills.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    loop_count: Option<u32>,
+}
+
+impl ImageInfo {
+    fn still(format: &Format, width: u32, height: u32) -> Self {
+        ImageInfo {
+            format: format.to_string(),
+            width,
+            height,
+            frames: 1,
+            pages: 1,
+            duration_ms: None,
+            loop_count: None,
+        }
+    }
+}
+
+impl Format {
+    fn info(
+        &mut self,
+        mut input: impl BufRead + Seek,
+        max_pixels: u64,
+    ) -> anyhow::Result<ImageInfo> {
+        match self {
+            // Image sequences are described by their track's headers. aom-decode only
+            // exposes a still's dimensions after a full decode.
+            Format::Avif => {
+                let mut buf = Vec::new();
+                input.read_to_end(&mut buf)?;
+
+                if find_box(&buf, b"moov").is_some() {
+                    return avif_sequence(&buf)
+                        .context("Could not read the AVIF image sequence's track");
+                }
+
+                let decoded = self.decode(Cursor::new(buf), max_pixels)?;
+
+                Ok(ImageInfo::still(self, decoded.width, decoded.height))
+            }
+            Format::Png => {
+                let mut buf = Vec::new();
+                input.read_to_end(&mut buf)?;
+
+                let reader = png::Decoder::new(buf.as_slice())
+                    .read_info()
+                    .context(Error::CouldNotReadInfo(Format::Png))?;
+
+                let info = reader.info();
+                let mut image = ImageInfo::still(self, info.width, info.height);
+
+                if let Some(actl) = info.animation_control {
+                    image.frames = actl.num_frames;
+                    image.duration_ms = Some(apng_duration(&buf));
+                    image.loop_count = Some(actl.num_plays);
+                }
+
+                Ok(image)
+            }
+            Format::Jpeg => {
+                let decoder = mozjpeg::Decompress::builder()
+                    .from_reader(&mut input)
+                    .context(Error::CouldNotReadInfo(Format::Jpeg))?;
+
+                Ok(ImageInfo::still(
+                    self,
+                    decoder.width() as u32,
+                    decoder.height() as u32,
+                ))
+            }
+            Format::WebP => {
+                let decoder = image_webp::WebPDecoder::new(&mut input)
+                    .context(Error::CouldNotReadInfo(Format::WebP))?;
+
+                let (width, height) = decoder.dimensions();
+                let mut image = ImageInfo::still(self, width, height);
+
+                if decoder.is_animated() {
+                    image.frames = decoder.num_frames();
+                    image.duration_ms = Some(decoder.loop_duration());
+                    image.loop_count = Some(match decoder.loop_count() {
+                        image_webp::LoopCount::Forever => 0,
+                        image_webp::LoopCount::Times(n) => n.get() as u32,
+                    });
+                }
+
+                Ok(image)
+            }
+            Format::Tiff => {
+                let mut decoder = tiff::decoder::Decoder::new(input)
+                    .context(Error::CouldNotReadInfo(Format::Tiff))?;
+
+                let (width, height) = decoder.dimensions()?;
+                let mut image = ImageInfo::still(self, width, height);
+                image.pages = tiff_page_count(&mut decoder)?;
+
+                Ok(image)
+            }
+            Format::Pnm => {
+                let mut buf = Vec::new();
+                input.read_to_end(&mut buf)?;
+
+                let header = pnm::header(&buf)?;
+
+                Ok(ImageInfo::still(self, header.width, header.height))
+            }
+            Format::Raw => anyhow::bail!(Error::OutputOnly(Format::Raw)),
+        }
+    }
+}
+
+/// Sums the frame delays of every fcTL chunk in an APNG, in milliseconds, without
+/// decoding any image data.
+fn apng_duration(png: &[u8]) -> u64 {
+    const SIGNATURE_LEN: usize = 8;
+
+    let mut total_ms = 0.;
+    let mut pos = SIGNATURE_LEN;
+
+    while let Some(header) = png.get(pos..pos + 8) {
+        let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let data = pos + 8;
+
+        if &header[4..] == b"fcTL" {
+            // delay_num and delay_den follow the sequence number and frame region.
+            if let Some(delay) = png.get(data + 20..data + 24) {
+                let num = u16::from_be_bytes([delay[0], delay[1]]) as f64;
+                let den = match u16::from_be_bytes([delay[2], delay[3]]) {
+                    // A zero denominator means hundredths of a second.
+                    0 => 100.,
+                    den => den as f64,
+                };
+
+                total_ms += num / den * 1000.;
+            }
+        }
+
+        if &header[4..] == b"IEND" {
+            break;
+        }
+
+        // Chunk data is followed by a 4-byte CRC.
+        pos = data + len + 4;
+    }
+
+    total_ms.round() as u64
+}
+
+/// Describes an AVIF image sequence (`avis`) from its first picture track: the frame
+/// count from `stsz`, one pass's length from `mdhd`, and the dimensions from `tkhd`. The
+/// track loops when its edit list is flagged to repeat, for as long as `tkhd` says, with
+/// an all-ones duration meaning forever.
+fn avif_sequence(avif: &[u8]) -> Option<ImageInfo> {
+    let moov = find_box(avif, b"moov")?;
+
+    let trak = boxes(moov)
+        .filter(|(kind, _)| *kind == b"trak")
+        .map(|(_, trak)| trak)
+        .find(|trak| {
+            find_box(trak, b"mdia")
+                .and_then(|mdia| find_box(mdia, b"hdlr"))
+                .and_then(|hdlr| hdlr.get(8..12))
+                == Some(b"pict")
+        })?;
+
+    let tkhd = find_box(trak, b"tkhd")?;
+    let mdia = find_box(trak, b"mdia")?;
+    let mdhd = find_box(mdia, b"mdhd")?;
+    let stbl = find_box(find_box(mdia, b"minf")?, b"stbl")?;
+
+    // Past the full box header, sample_size and sample_count.
+    let frames = u32_at(find_box(stbl, b"stsz")?, 8)?;
+
+    // mdhd: the full box header, creation and modification times, timescale, duration.
+    let (timescale, pass) = match mdhd.first()? {
+        1 => (u32_at(mdhd, 20)?, u64_at(mdhd, 24)?),
+        _ => (u32_at(mdhd, 12)?, u32_at(mdhd, 16)? as u64),
+    };
+    let duration_ms = pass.checked_mul(1000)? / timescale.max(1) as u64;
+
+    // tkhd: as mdhd, but with a track ID and a reserved field before the duration, then
+    // 52 bytes of layer, volume and matrix fields before the 16.16 fixed point size.
+    let (track_duration, size) = match tkhd.first()? {
+        1 => (Some(u64_at(tkhd, 28)?).filter(|&d| d != u64::MAX), 88),
+        _ => (
+            Some(u32_at(tkhd, 20)?)
+                .filter(|&d| d != u32::MAX)
+                .map(u64::from),
+            76,
+        ),
+    };
+    let width = u32_at(tkhd, size)? >> 16;
+    let height = u32_at(tkhd, size + 4)? >> 16;
+
+    // Both the edit list and tkhd are in the movie's timescale.
+    let repeating = find_box(trak, b"edts")
+        .and_then(|edts| find_box(edts, b"elst"))
+        .filter(|elst| elst.get(3).is_some_and(|flags| flags & 1 == 1));
+
+    let loop_count = match (repeating, track_duration) {
+        (None, _) => 1,
+        (Some(_), None) => 0,
+        (Some(elst), Some(track_duration)) => {
+            // The first entry's segment_duration, past the entry count.
+            let segment = match elst.first()? {
+                1 => u64_at(elst, 8)?,
+                _ => u32_at(elst, 8)? as u64,
+            };
+
+            (track_duration / segment.max(1)).max(1) as u32
+        }
+    };
+
+    Some(ImageInfo {
+        format: Format::Avif.to_string(),
+        width,
+        height,
+        frames,
+        pages: 1,
+        duration_ms: Some(duration_ms),
+        loop_count: Some(loop_count),
+    })
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+pub async fn info(
+    config: web::Data<Config>,
+    MultipartForm(InfoForm {
+        file: input,
+    }): MultipartForm<InfoForm>,
+) -> impl Responder {
+    let Some(mut format) = upload_format(&input) else {
+        return HttpResponse::BadRequest().body("Unsupported input type");
+    };
+
+    if !config.allowed_input_formats.contains(format) {
+        return disallowed_input(format);
+    }
+
+    let file = std::io::BufReader::new(input.file.into_file());
+
+    match format.info(file, config.max_input_pixels) {
+        Ok(info) => HttpResponse::Ok().json(info),
+        Err(e) => HttpResponse::UnprocessableEntity().body(format!("{e:#}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bx(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        [&(8 + payload.len() as u32).to_be_bytes(), kind, payload].concat()
+    }
+
+    fn be(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    /// A version 0 track of `frames` frames and `handler` type, 640x480, one second per
+    /// pass at a 1000 Hz timescale, lasting `track_duration` in all. `repeat` sets the
+    /// edit list's repeat flag.
+    fn trak(handler: &[u8; 4], frames: u32, track_duration: u32, repeat: bool) -> Vec<u8> {
+        let tkhd = [
+            be(&[0, 0, 0, 1, 0, track_duration]),
+            vec![0; 52],
+            be(&[640 << 16, 480 << 16]),
+        ]
+        .concat();
+        let hdlr = [be(&[0, 0]), handler.to_vec(), vec![0; 13]].concat();
+        let mdhd = be(&[0, 0, 0, 1000, 1000, 0]);
+        let stbl = bx(b"stsz", &be(&[0, 0, frames]));
+        let elst = be(&[repeat as u32, 1, 1000, 0, 1 << 16]);
+
+        let mdia = [
+            bx(b"mdhd", &mdhd),
+            bx(b"hdlr", &hdlr),
+            bx(b"minf", &bx(b"stbl", &stbl)),
+        ]
+        .concat();
+
+        bx(
+            b"trak",
+            &[
+                bx(b"tkhd", &tkhd),
+                bx(b"edts", &bx(b"elst", &elst)),
+                bx(b"mdia", &mdia),
+            ]
+            .concat(),
+        )
+    }
+
+    /// An `avis` file with an alpha track ahead of the color one.
+    fn avis(track_duration: u32, repeat: bool) -> Vec<u8> {
+        let moov = [
+            trak(b"auxv", 99, 99, false),
+            trak(b"pict", 10, track_duration, repeat),
+        ]
+        .concat();
+
+        [bx(b"ftyp", b"avismif1"), bx(b"moov", &moov)].concat()
+    }
+
+    #[test]
+    fn reads_avif_sequences_from_their_tracks() {
+        let info = Format::Avif
+            .info(Cursor::new(avis(3000, true)), u64::MAX)
+            .unwrap();
+
+        assert_eq!((info.width, info.height), (640, 480));
+        assert_eq!(info.frames, 10);
+        assert_eq!(info.duration_ms, Some(1000));
+        assert_eq!(info.loop_count, Some(3));
+    }
+
+    #[test]
+    fn avif_sequences_loop_per_their_edit_list() {
+        let loop_count = |track_duration, repeat| {
+            avif_sequence(&avis(track_duration, repeat))
+                .unwrap()
+                .loop_count
+        };
+
+        assert_eq!(loop_count(3000, false), Some(1));
+        assert_eq!(loop_count(u32::MAX, true), Some(0));
+    }
+
+    #[test]
+    fn malformed_avif_sequences_are_errors() {
+        let mut avif = avis(1000, false);
+        let stsz = avif.windows(4).rposition(|w| w == b"stsz").unwrap();
+        avif[stsz..stsz + 4].copy_from_slice(b"free");
+
+        let error = Format::Avif.info(Cursor::new(avif), u64::MAX).unwrap_err();
+
+        assert!(error.to_string().contains("image sequence"), "{error:#}");
+    }
+}
@@ -0,0 +1,551 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::BufReader,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_multipart::form::{json::Json, tempfile::TempFile, MultipartForm};
+use actix_web::{http::header::RETRY_AFTER, web, HttpRequest, HttpResponse, Responder};
+use anyhow::{ensure, Context};
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+use crate::{
+    archive::{self, Archive},
+    budget::{self, CpuBudget},
+    config::Config,
+    disallowed_output, output_format, upload_format, upload_stem, EncodeOptions,
+};
+
+/// Most files one job may contain.
+const MAX_JOB_FILES: usize = 100;
+/// How long clients are told to wait when the server is holding too many jobs.
+const JOB_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+#[derive(Debug, MultipartForm)]
+pub struct JobForm {
+    #[multipart(rename = "file")]
+    files: Vec<TempFile>,
+    /// Left out, each file is re-encoded to its own format with size-optimizing settings,
+    /// keeping the original where that's no smaller unless `auto_orient` or
+    /// `max_dimension` changed it.
+    output_type: Option<Json<String>>,
+    quality: Option<Json<f32>>,
+    /// Rotate/flip according to each source's EXIF orientation.
+    auto_orient: Option<Json<bool>>,
+    /// Scale down, preserving aspect ratio, so neither side exceeds this many pixels.
+    max_dimension: Option<Json<u32>>,
+    /// Add a `manifest.json` to the archive listing every file's result, as `GET
+    /// /jobs/{id}` reports them.
+    manifest: Option<Json<bool>>,
+}
+
+/// Settings shared by every file in a job.
+#[derive(Debug)]
+struct JobSettings {
+    config: web::Data<Config>,
+    /// `None` to optimize each file in place.
+    format: Option<crate::Format>,
+    quality: f32,
+    auto_orient: bool,
+    max_dimension: Option<u32>,
+    manifest: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Queued,
+    Running,
+    Done,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum FileResult {
+    Done {
+        /// Name of the output in the archive.
+        name: String,
+        /// Name of the uploaded file, if it had one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source: Option<String>,
+        input_format: &'static str,
+        output_format: &'static str,
+        width: u32,
+        height: u32,
+        input_bytes: usize,
+        bytes: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes_saved: Option<usize>,
+        /// Information the conversion lost or changed along the way.
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        warnings: Vec<String>,
+    },
+    Failed {
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source: Option<String>,
+        error: String,
+    },
+}
+
+/// One successfully converted file.
+struct Converted {
+    out: Vec<u8>,
+    input_format: crate::Format,
+    output_format: crate::Format,
+    width: u32,
+    height: u32,
+    bytes_saved: Option<usize>,
+    warnings: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Job {
+    status: Status,
+    total: usize,
+    files: Vec<FileResult>,
+    /// ZIP of every converted file, once the job is done.
+    archive: Option<Vec<u8>>,
+    /// When the job finished, for expiry.
+    finished: Option<Instant>,
+}
+
+#[derive(Debug, Serialize)]
+struct JobView<'a> {
+    id: Uuid,
+    status: Status,
+    total: usize,
+    files: &'a [FileResult],
+    /// Where to download the converted files from, once done.
+    result: Option<String>,
+}
+
+/// Conversion jobs submitted to `/jobs`, kept in memory until `JOB_TTL` after they finish.
+/// At most `JOB_CONCURRENCY` jobs are processed at once; the rest wait their turn. No more
+/// than `MAX_JOBS` are held at a time, nor `MAX_JOB_STORAGE` bytes of archives.
+#[derive(Debug)]
+pub struct Jobs {
+    jobs: Mutex<HashMap<Uuid, Job>>,
+    permits: Arc<Semaphore>,
+    ttl: Duration,
+    max_jobs: usize,
+    max_storage: usize,
+}
+
+impl Jobs {
+    pub fn new(concurrency: usize, ttl: Duration, max_jobs: usize, max_storage: usize) -> Self {
+        Jobs {
+            jobs: Mutex::default(),
+            permits: Arc::new(Semaphore::new(concurrency)),
+            ttl,
+            max_jobs,
+            max_storage,
+        }
+    }
+
+    /// Why another job can't be taken on right now, if it can't.
+    fn full(&self, jobs: &HashMap<Uuid, Job>) -> Option<&'static str> {
+        let storage: usize = jobs
+            .values()
+            .filter_map(|job| job.archive.as_ref())
+            .map(Vec::len)
+            .sum();
+
+        if jobs.len() >= self.max_jobs {
+            Some("Too many jobs in progress")
+        } else if storage >= self.max_storage {
+            Some("Too many job results awaiting download")
+        } else {
+            None
+        }
+    }
+
+    /// Forgets jobs that finished more than `ttl` ago.
+    fn expire(&self, jobs: &mut HashMap<Uuid, Job>) {
+        jobs.retain(|_, job| job.finished.is_none_or(|at| at.elapsed() < self.ttl));
+    }
+
+    fn update(&self, id: Uuid, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().expect("jobs lock poisoned").get_mut(&id) {
+            f(job);
+        }
+    }
+}
+
+/// Accepts a batch of files and returns a job ID at once, converting them in the
+/// background. Poll `GET /jobs/{id}` for progress.
+pub async fn submit(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    jobs: web::Data<Jobs>,
+    cpu_budget: web::Data<CpuBudget>,
+    MultipartForm(JobForm {
+        files,
+        output_type,
+        quality,
+        auto_orient,
+        max_dimension,
+        manifest,
+    }): MultipartForm<JobForm>,
+) -> actix_web::Result<impl Responder, actix_web::Error> {
+    let format = match output_type {
+        Some(output_type) => match output_format(&output_type) {
+            Some(format) if !config.allowed_output_formats.contains(format) => {
+                return Ok(disallowed_output(format))
+            }
+            Some(format) => Some(format),
+            None => return Ok(HttpResponse::BadRequest().body("Unsupported output type")),
+        },
+        None => None,
+    };
+
+    if files.is_empty() || files.len() > MAX_JOB_FILES {
+        return Ok(HttpResponse::BadRequest().body(format!(
+            "A job must contain between 1 and {MAX_JOB_FILES} files"
+        )));
+    }
+
+    let quality = quality.map_or(config.default_quality, |q| *q);
+
+    if !(1. ..=100.).contains(&quality) {
+        return Ok(HttpResponse::BadRequest().body("quality must be between 1 and 100"));
+    }
+
+    let max_dimension = max_dimension.map(|m| *m);
+
+    if max_dimension == Some(0) {
+        return Ok(HttpResponse::BadRequest().body("max_dimension must be positive"));
+    }
+
+    let settings = JobSettings {
+        config,
+        format,
+        quality,
+        auto_orient: auto_orient.is_some_and(|a| *a),
+        max_dimension,
+        manifest: manifest.is_some_and(|m| *m),
+    };
+
+    let id = Uuid::new_v4();
+    let total = files.len();
+
+    {
+        let mut map = jobs.jobs.lock().expect("jobs lock poisoned");
+        jobs.expire(&mut map);
+
+        if let Some(reason) = jobs.full(&map) {
+            return Ok(HttpResponse::ServiceUnavailable()
+                .insert_header((RETRY_AFTER, JOB_RETRY_AFTER.as_secs()))
+                .body(format!("{reason}, try again later")));
+        }
+
+        map.insert(
+            id,
+            Job {
+                status: Status::Queued,
+                total,
+                files: Vec::new(),
+                archive: None,
+                finished: None,
+            },
+        );
+    }
+
+    let jobs = jobs.into_inner();
+    // The job outlives the request, so its encode time is charged once it's done.
+    let client = cpu_budget.client(&req);
+
+    actix_web::rt::spawn(async move {
+        let _permit = jobs
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the job semaphore is never closed");
+
+        jobs.update(id, |job| job.status = Status::Running);
+
+        let archive = web::block({
+            let jobs = jobs.clone();
+
+            move || budget::measure(|| run(id, files, &settings, &jobs))
+        })
+        .await
+        .map(|(archive, spent)| {
+            if let Some(client) = client {
+                cpu_budget.charge(client, spent);
+            }
+
+            archive
+        });
+
+        jobs.update(id, |job| {
+            match archive {
+                Ok(Ok(archive)) => job.archive = Some(archive),
+                Ok(Err(e)) => log::error!("Job {id} failed to build its archive: {e:#}"),
+                Err(e) => log::error!("Job {id} was cancelled: {e}"),
+            }
+
+            job.status = Status::Done;
+            job.finished = Some(Instant::now());
+        });
+    });
+
+    Ok(HttpResponse::Accepted().json(JobView {
+        id,
+        status: Status::Queued,
+        total,
+        files: &[],
+        result: None,
+    }))
+}
+
+/// Converts every file, recording each result as it completes, and zips the outputs.
+fn run(
+    id: Uuid,
+    files: Vec<TempFile>,
+    settings: &JobSettings,
+    jobs: &Jobs,
+) -> anyhow::Result<Vec<u8>> {
+    let mut archive = Archive::new();
+    let mut names = HashSet::new();
+    let mut results = Vec::new();
+
+    for file in files {
+        let stem = upload_stem(&file);
+        let source = file.file_name.clone();
+        let input_bytes = file.size;
+        let format = settings.format.or_else(|| upload_format(&file));
+        let extension = format.map_or("bin", |format| format.extension());
+
+        let name = unique_name(&mut names, &stem, extension);
+
+        let result = match convert(file, settings) {
+            Ok(converted) => {
+                archive.add_image(&name, &converted.out)?;
+
+                FileResult::Done {
+                    name,
+                    source,
+                    input_format: converted.input_format.name(),
+                    output_format: converted.output_format.name(),
+                    width: converted.width,
+                    height: converted.height,
+                    input_bytes,
+                    bytes: converted.out.len(),
+                    bytes_saved: converted.bytes_saved,
+                    warnings: converted.warnings,
+                }
+            }
+            Err(e) => FileResult::Failed {
+                name,
+                source,
+                error: format!("{e:#}"),
+            },
+        };
+
+        if settings.manifest {
+            results.push(result.clone());
+        }
+
+        jobs.update(id, |job| job.files.push(result));
+    }
+
+    if settings.manifest {
+        archive.add_manifest(&results)?;
+    }
+
+    archive.finish()
+}
+
+/// `{stem}.{extension}`, or if that's taken, `{stem}-{n}.{extension}` with the lowest `n`
+/// that isn't, since another upload may really be called `{stem}-1`. Adds it to `names`.
+fn unique_name(names: &mut HashSet<String>, stem: &str, extension: &str) -> String {
+    let mut name = format!("{stem}.{extension}");
+
+    for n in 1.. {
+        if names.insert(name.clone()) {
+            break;
+        }
+
+        name = format!("{stem}-{n}.{extension}");
+    }
+
+    name
+}
+
+fn convert(file: TempFile, settings: &JobSettings) -> anyhow::Result<Converted> {
+    let mut input_format = upload_format(&file).context("Unsupported input type")?;
+
+    let config = &settings.config;
+
+    ensure!(
+        config.allowed_input_formats.contains(input_format),
+        "{input_format} input isn't allowed on this instance"
+    );
+
+    let original = match settings.format {
+        Some(_) => None,
+        None => Some(std::fs::read(file.file.path())?),
+    };
+
+    let mut decoded = input_format.decode(
+        BufReader::new(file.file.into_file()),
+        config.max_input_pixels,
+    )?;
+
+    if settings.auto_orient {
+        if let Some(orientation) = decoded.metadata.orientation() {
+            decoded.orient(orientation);
+        }
+    }
+
+    if let Some(max) = settings.max_dimension {
+        decoded.fit_within(max)?;
+    }
+
+    let mut format = settings.format.unwrap_or(input_format);
+    let mut warnings = Vec::new();
+
+    let had_alpha = decoded.color_type.has_alpha();
+    decoded.adapt_layout_for(&format);
+
+    if had_alpha && !decoded.color_type.has_alpha() {
+        warnings.push(format!(
+            "{format} can't store transparency, so it was flattened onto white"
+        ));
+    }
+
+    if decoded.bit_depth == 16 && !format.supports_16bit() {
+        warnings.push(format!(
+            "{format} can't store 16-bit samples, so they were scaled to 8 bits"
+        ));
+    }
+
+    let options = EncodeOptions {
+        quality: config
+            .max_quality(&format)
+            .map_or(settings.quality, |max| settings.quality.min(max)),
+        png_compression: match settings.format {
+            Some(_) => config.profile.png_compression(),
+            None => png::Compression::Best,
+        },
+        ..EncodeOptions::defaults(config, format)
+    };
+
+    let out = format.encode(&decoded, &options)?;
+
+    // Transformed output isn't interchangeable with the original, however big it is.
+    let transformed = settings.auto_orient || settings.max_dimension.is_some();
+
+    let (out, bytes_saved) = match original {
+        Some(original) if !transformed && original.len() <= out.len() => {
+            warnings.clear();
+            warnings.push("Re-encoding wasn't smaller, so the original was kept".to_string());
+
+            (original, Some(0))
+        }
+        Some(original) => {
+            let saved = original.len().saturating_sub(out.len());
+
+            (out, Some(saved))
+        }
+        None => (out, None),
+    };
+
+    Ok(Converted {
+        out,
+        input_format,
+        output_format: format,
+        width: decoded.width,
+        height: decoded.height,
+        bytes_saved,
+        warnings,
+    })
+}
+
+pub async fn status(jobs: web::Data<Jobs>, id: web::Path<Uuid>) -> impl Responder {
+    let mut map = jobs.jobs.lock().expect("jobs lock poisoned");
+    jobs.expire(&mut map);
+
+    let Some(job) = map.get(&id) else {
+        return HttpResponse::NotFound().body("No such job");
+    };
+
+    HttpResponse::Ok().json(JobView {
+        id: *id,
+        status: job.status,
+        total: job.total,
+        files: &job.files,
+        result: job.archive.is_some().then(|| format!("/jobs/{id}/result")),
+    })
+}
+
+/// Downloads a finished job's outputs as a ZIP.
+pub async fn result(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    jobs: web::Data<Jobs>,
+    id: web::Path<Uuid>,
+) -> actix_web::Result<impl Responder, actix_web::Error> {
+    let archive = {
+        let mut map = jobs.jobs.lock().expect("jobs lock poisoned");
+        jobs.expire(&mut map);
+
+        match map.get(&id) {
+            Some(Job {
+                archive: Some(archive),
+                ..
+            }) => archive.clone(),
+            Some(Job {
+                status: Status::Done,
+                ..
+            }) => return Ok(HttpResponse::InternalServerError().body("Job produced no archive")),
+            Some(_) => return Ok(HttpResponse::Conflict().body("Job hasn't finished")),
+            None => return Ok(HttpResponse::NotFound().body("No such job")),
+        }
+    };
+
+    archive::respond(archive, format!("job-{id}.zip"), &config, &req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_names_never_collide() {
+        let mut names = HashSet::new();
+
+        let names: Vec<_> = [("a", "png"), ("a-1", "png"), ("a", "png"), ("a", "jpg")]
+            .into_iter()
+            .map(|(stem, extension)| unique_name(&mut names, stem, extension))
+            .collect();
+
+        assert_eq!(names, ["a.png", "a-1.png", "a-2.png", "a.jpg"]);
+    }
+
+    #[test]
+    fn refuses_jobs_past_the_caps() {
+        let jobs = Jobs::new(1, Duration::from_secs(60), 2, 10);
+        let job = |archive: Option<Vec<u8>>| Job {
+            status: Status::Done,
+            total: 0,
+            files: Vec::new(),
+            archive,
+            finished: None,
+        };
+
+        let mut map = HashMap::from([(Uuid::new_v4(), job(Some(vec![0; 5])))]);
+        assert_eq!(jobs.full(&map), None);
+
+        map.insert(Uuid::new_v4(), job(None));
+        assert!(jobs.full(&map).is_some());
+
+        let map = HashMap::from([(Uuid::new_v4(), job(Some(vec![0; 10])))]);
+        assert!(jobs.full(&map).is_some());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{histogram::Histograms, ColorType, Decoded};
+
+/// Percentage of pixels ignored at each end of the histogram when the request doesn't
+/// say, so a few stray specks don't pin the range.
+pub const DEFAULT_CLIP: f64 = 0.5;
+
+/// How `auto_levels` stretches color images.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LevelsMode {
+    /// One stretch, taken from the luminance histogram, applied to every channel alike, so
+    /// hues are kept.
+    Luminance,
+    /// Each channel stretched on its own, which also neutralizes color casts.
+    PerChannel,
+}
+
+impl LevelsMode {
+    pub fn from_name(name: &str) -> Option<LevelsMode> {
+        match name {
+            "luminance" => Some(LevelsMode::Luminance),
+            "per_channel" => Some(LevelsMode::PerChannel),
+
+            _ => None,
+        }
+    }
+}
+
+/// The darkest and brightest 8-bit values left once `clip` percent of the pixels are
+/// ignored at each end, or `None` if there's no range left to stretch.
+fn bounds(histogram: &[u32], clip: f64) -> Option<(u32, u32)> {
+    let total: u64 = histogram.iter().map(|&count| count as u64).sum();
+    let skip = (total as f64 * clip / 100.) as u64;
+
+    let low = first_past(histogram.iter(), skip)?;
+    let high = histogram.len() - 1 - first_past(histogram.iter().rev(), skip)?;
+
+    (high > low).then_some((low as u32, high as u32))
+}
+
+/// Position of the first bin at which more than `skip` pixels have been seen.
+fn first_past<'a>(mut bins: impl Iterator<Item = &'a u32>, skip: u64) -> Option<usize> {
+    let mut seen = 0;
+
+    bins.position(|&count| {
+        seen += count as u64;
+        seen > skip
+    })
+}
+
+impl Decoded {
+    /// Stretches the tonal range so the darkest pixels become black and the brightest
+    /// white, ignoring `clip` percent of pixels at either end. Alpha is left alone.
+    pub fn auto_levels(&mut self, clip: f64, mode: LevelsMode) {
+        self.convert_to_rgb();
+
+        let histograms = Histograms::compute(&self.rgba_pixels());
+        let (Some(red), Some(green), Some(blue), Some(luminance)) = (
+            histograms.red,
+            histograms.green,
+            histograms.blue,
+            histograms.luminance,
+        ) else {
+            return;
+        };
+
+        let color_channels = match self.color_type {
+            ColorType::Grayscale | ColorType::GrayscaleAlpha => 1,
+            _ => 3,
+        };
+
+        // Gray images have identical red, green, blue and luminance histograms anyway.
+        let ranges: Vec<_> = match mode {
+            LevelsMode::Luminance => vec![bounds(&luminance, clip); color_channels],
+            LevelsMode::PerChannel => [red, green, blue][..color_channels]
+                .iter()
+                .map(|histogram| bounds(histogram, clip))
+                .collect(),
+        };
+
+        let wide = self.bit_depth == 16;
+        let max: u32 = if wide { 65535 } else { 255 };
+
+        // One lookup table per color channel, scaling the 8-bit bounds up for 16-bit
+        // samples.
+        let tables: Vec<Option<Vec<u16>>> = ranges
+            .iter()
+            .map(|range| {
+                let (low, high) = range.map(|(low, high)| match wide {
+                    true => (low * 257, high * 257),
+                    false => (low, high),
+                })?;
+
+                Some(
+                    (0..=max)
+                        .map(|v| {
+                            let stretched =
+                                (v.saturating_sub(low) * max + (high - low) / 2) / (high - low);
+
+                            stretched.min(max) as u16
+                        })
+                        .collect(),
+                )
+            })
+            .collect();
+
+        let channels = self.color_type.channels();
+
+        match wide {
+            true => {
+                for (i, sample) in self.bytes.chunks_exact_mut(2).enumerate() {
+                    if let Some(Some(table)) = tables.get(i % channels) {
+                        let v = u16::from_ne_bytes([sample[0], sample[1]]);
+
+                        sample.copy_from_slice(&table[v as usize].to_ne_bytes());
+                    }
+                }
+            }
+            false => {
+                for (i, sample) in self.bytes.iter_mut().enumerate() {
+                    if let Some(Some(table)) = tables.get(i % channels) {
+                        *sample = table[*sample as usize] as u8;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Metadata;
+
+    #[test]
+    fn low_contrast_gradients_stretch_to_the_full_range() {
+        // A horizontal ramp over 100-150 only.
+        let mut image = Decoded {
+            bytes: (100..=150).collect(),
+            color_type: ColorType::Grayscale,
+            width: 51,
+            height: 1,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        };
+
+        image.auto_levels(0., LevelsMode::Luminance);
+
+        assert_eq!(image.bytes.first(), Some(&0));
+        assert_eq!(image.bytes.last(), Some(&255));
+        assert_eq!(
+            image.bytes,
+            (0..=50)
+                .map(|i| ((i * 255 + 25) / 50) as u8)
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Serialize;
+
+use crate::{Decoded, EncodeOptions, Format};
+
+/// Longest side of a placeholder; browsers stretch it back up, which blurs it further.
+const LQIP_SIZE: u32 = 16;
+/// Placeholders are meant to be blurry, so spend as few bytes as possible.
+const LQIP_QUALITY: f32 = 40.;
+
+/// Response body for `with_lqip` conversions, carrying the output and its placeholder.
+#[derive(Debug, Serialize)]
+pub struct WithLqip<'a> {
+    /// MIME type of `image`.
+    content_type: &'a str,
+    /// The converted image, base64-encoded.
+    image: String,
+    /// A `data:` URI of the placeholder.
+    lqip: String,
+}
+
+impl<'a> WithLqip<'a> {
+    pub fn new(content_type: &'a str, image: &[u8], lqip: String) -> Self {
+        WithLqip {
+            content_type,
+            image: STANDARD.encode(image),
+            lqip,
+        }
+    }
+}
+
+impl Decoded {
+    /// Builds a tiny blurred copy of the image as a `data:` URI, for use as an `<img>`
+    /// placeholder while the full image loads. Opaque images become JPEGs and the rest
+    /// PNGs.
+    pub fn lqip(&self) -> anyhow::Result<String> {
+        let mut tiny = self.clone();
+        tiny.reduce_to_8bit();
+        tiny.convert_to_rgb();
+        tiny.fit_within(LQIP_SIZE)?;
+        tiny.box_blur();
+
+        let mut format = match tiny.color_type.has_alpha() {
+            true => Format::Png,
+            false => Format::Jpeg,
+        };
+
+        let encoded = format.encode(
+            &tiny,
+            &EncodeOptions {
+                png_compression: png::Compression::Best,
+                ..EncodeOptions::quick(LQIP_QUALITY)
+            },
+        )?;
+
+        let mime = match format {
+            Format::Png => "image/png",
+            _ => "image/jpeg",
+        };
+
+        Ok(format!("data:{mime};base64,{}", STANDARD.encode(encoded)))
+    }
+
+    /// Averages each sample of an 8-bit image with its 3x3 neighborhood.
+    fn box_blur(&mut self) {
+        let channels = self.color_type.channels();
+        let (width, height) = (self.width as usize, self.height as usize);
+
+        let blurred = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| (0..channels).map(move |c| (x, y, c)))
+            .map(|(x, y, c)| {
+                let (mut sum, mut count) = (0u32, 0u32);
+
+                for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                        sum += self.bytes[(ny * width + nx) * channels + c] as u32;
+                        count += 1;
+                    }
+                }
+
+                ((sum + count / 2) / count) as u8
+            })
+            .collect();
+
+        self.bytes = blurred;
+    }
+}
@@ -1,30 +1,208 @@
 use std::{
+    borrow::Cow,
     fmt::Display,
-    io::{BufRead, Seek},
+    io::{BufRead, Cursor, Seek, Write},
 };
 
 use actix_cors::Cors;
-use actix_multipart::form::{json::Json, tempfile::TempFile, MultipartForm};
-use actix_web::{post, App, HttpResponse, HttpServer, Responder};
+use actix_multipart::form::{
+    json::Json,
+    tempfile::{TempFile, TempFileConfig},
+    MultipartForm,
+};
+use actix_web::{
+    dev::Service,
+    http::{
+        header::{HeaderName, HeaderValue, X_CONTENT_TYPE_OPTIONS},
+        KeepAlive, Method,
+    },
+    middleware::{Compress, DefaultHeaders, Logger},
+    web, App, HttpRequest, HttpResponse, HttpServer, Responder,
+};
 use anyhow::{bail, Context};
 use aom_decode::Config;
 use ravif::Img;
-use rgb::{ComponentMap, FromSlice};
+use rgb::RGBA8;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+mod archive;
+mod autocrop;
+mod bench;
+mod budget;
+mod cache;
+mod colors;
+mod config;
+mod convert;
+mod frames;
+mod health;
+mod histogram;
+mod info;
+mod jobs;
+mod levels;
+mod lqip;
+mod orient;
+mod output;
+mod pnm;
+mod profiles;
+mod request_id;
+mod resize;
+mod signing;
+mod srcset;
+mod srgb;
+mod upload;
+
+/// Output type for `/convert_raw`, when it isn't in the query string.
+const X_OUTPUT_TYPE: HeaderName = HeaderName::from_static("x-output-type");
+
+/// Per-channel tolerance used by `autocrop` when the request doesn't specify one.
+const DEFAULT_AUTOCROP_TOLERANCE: u8 = 10;
+
 #[derive(Debug, MultipartForm)]
 struct UploadForm {
-    #[multipart(limit = "25MB")]
     file: TempFile,
-    output_type: Json<String>,
+    /// Format to convert to. Left out, the upload is re-encoded to its own format with
+    /// size-optimizing settings, and the original is returned if that's no smaller and no
+    /// other option was given; the bytes saved are sent back in `X-Bytes-Saved`.
+    ///
+    /// `raw` returns the pixel buffer itself, after any transforms, with its layout in
+    /// `X-Image-Width`, `X-Image-Height`, `X-Image-Color-Type` and `X-Image-Bit-Depth`.
+    output_type: Option<Json<String>>,
+    autocrop: Option<Json<bool>>,
+    autocrop_tolerance: Option<Json<u8>>,
+    jpeg_smoothing: Option<Json<u8>>,
+    /// Emit a JPEG restart marker every this many MCUs (1-65535), so decoders can
+    /// resynchronize after corruption. Off by default. Uses a baseline encoder in place of
+    /// mozjpeg, so can't be combined with `jpeg_smoothing` and compresses somewhat worse.
+    jpeg_restart_interval: Option<Json<u16>>,
+    /// JPEG/AVIF quality (1-100), defaulting to the instance's `DEFAULT_QUALITY`. Clamped
+    /// to the instance's per-format ceiling; the value used is sent back in
+    /// `X-Applied-Quality`.
+    quality: Option<Json<f32>>,
+    /// Rotate/flip according to the source's EXIF orientation.
+    auto_orient: Option<Json<bool>>,
+    /// How much of the EXIF orientation to apply: `full`, `rotate_only` (skip mirroring)
+    /// or `none`. Overrides `auto_orient`.
+    orient_mode: Option<Json<String>>,
+    /// Convert pixels from the source's embedded ICC profile into sRGB.
+    to_srgb: Option<Json<bool>>,
+    /// Stretch the tonal range to span black to white, for flat or hazy scans.
+    auto_levels: Option<Json<bool>>,
+    /// Percentage of pixels (0-25) `auto_levels` ignores at each end, so outliers don't
+    /// limit the stretch. Defaults to 0.5.
+    auto_levels_clip: Option<Json<f64>>,
+    /// `luminance` (the default) stretches every channel alike, keeping hues;
+    /// `per_channel` stretches each on its own, also removing color casts.
+    auto_levels_mode: Option<Json<String>>,
+    /// Scale down, preserving aspect ratio, so neither side exceeds this many pixels.
+    max_dimension: Option<Json<u32>>,
+    /// Preset for web delivery, equivalent to `auto_orient` + `to_srgb` + `max_dimension`
+    /// set to the instance's `WEB_READY_MAX_DIMENSION`. Converted outputs never carry
+    /// source metadata, so they're always stripped. Fields set explicitly override the preset.
+    web_ready: Option<Json<bool>>,
+    /// ravif speed (1-10) for this conversion, overriding the instance's profile. Raised
+    /// to the instance's `MIN_AVIF_SPEED` if lower.
+    avif_speed: Option<Json<u8>>,
+    /// Set to `false` to fail outright instead of retrying a failed AVIF encode at the
+    /// fastest speed.
+    avif_fallback: Option<Json<bool>>,
+    /// Resize relative to the source, e.g. `0.5` for half size. Applied before
+    /// `max_dimension`, and capped at the instance's `MAX_OUTPUT_DIMENSION`.
+    scale: Option<Json<f64>>,
+    /// Produce byte-identical output across repeated conversions of the same input, at
+    /// the cost of single-threaded AVIF encoding.
+    deterministic: Option<Json<bool>>,
+    /// Set to `8` to scale 16-bit sources down. Otherwise PNG and TIFF outputs keep the
+    /// source's bit depth.
+    output_bit_depth: Option<Json<u8>>,
+    /// Tag the output with a standard profile (`srgb`, `display-p3` or `rec2020`),
+    /// without transforming the pixels, for sources already in that space but untagged.
+    assign_profile: Option<Json<String>>,
+    /// Target width in pixels. Given alone, the height follows the aspect ratio.
+    width: Option<Json<u32>>,
+    /// Target height in pixels. Given alone, the width follows the aspect ratio.
+    height: Option<Json<u32>>,
+    /// How to fill a box when both `width` and `height` are set: `exact` (stretch),
+    /// `contain` (the default; fit inside and pad with `background`) or `cover` (fill and
+    /// crop).
+    fit: Option<Json<String>>,
+    /// Padding color for `fit: contain` as `#rrggbb` or `#rrggbbaa`, and the color
+    /// transparency is flattened onto for outputs without alpha; white by default.
+    background: Option<Json<String>>,
+    /// Resample sources that declare non-square pixels (PNG pHYs, JPEG JFIF density, TIFF
+    /// resolution or AVIF pasp) to square ones, so they keep their intended proportions.
+    correct_pixel_aspect: Option<Json<bool>>,
+    /// Which image of a multi-page TIFF to convert, counting from 0.
+    page: Option<Json<u32>>,
+    /// Respond with JSON holding the base64 output alongside `lqip`, a tiny blurred
+    /// `data:` URI to show while the full image loads.
+    with_lqip: Option<Json<bool>>,
+    /// Force the output channel layout: `rgb`, `rgba`, `grayscale` or `grayscale_alpha`.
+    output_color_type: Option<Json<String>>,
+    /// Answer 422 instead of silently losing information: flattening transparency (unless
+    /// `background` or `output_color_type` is set), scaling 16-bit samples down (unless `output_bit_depth`
+    /// is 8) or encoding to a lossy format (unless `quality` is set).
+    strict_fidelity: Option<Json<bool>>,
+    /// Set to `alpha` to output only the alpha channel, as a grayscale image. Sources
+    /// without one get a 400.
+    extract: Option<Json<String>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Format {
     Avif,
     Png,
     Jpeg,
     WebP,
+    Pnm,
+    Tiff,
+    /// The decoded pixel buffer as-is, described by response headers. Output only.
+    Raw,
+}
+
+impl Format {
+    /// Every format uploads can be in.
+    const INPUTS: [Format; 6] = [
+        Format::Avif,
+        Format::Png,
+        Format::Jpeg,
+        Format::WebP,
+        Format::Pnm,
+        Format::Tiff,
+    ];
+
+    /// Parses a `name`, including `raw`, which only `/convert_image` produces.
+    fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "raw" => Some(Format::Raw),
+            name => output_format(name),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Avif => "avif",
+            Format::Png => "png",
+            Format::Jpeg => "jpg",
+            Format::WebP => "webp",
+            Format::Pnm => "pnm",
+            Format::Tiff => "tiff",
+            Format::Raw => "bin",
+        }
+    }
+
+    /// The `output_type` that selects this format.
+    fn name(&self) -> &'static str {
+        match self {
+            Format::Avif => "avif",
+            Format::Png => "png",
+            Format::Jpeg => "jpeg",
+            Format::WebP => "webp",
+            Format::Pnm => "pnm",
+            Format::Tiff => "tiff",
+            Format::Raw => "raw",
+        }
+    }
 }
 
 impl Display for Format {
@@ -36,11 +214,14 @@ impl Display for Format {
             Png => write!(f, "PNG"),
             Jpeg => write!(f, "JPEG"),
             WebP => write!(f, "WebP"),
+            Pnm => write!(f, "PNM"),
+            Tiff => write!(f, "TIFF"),
+            Raw => write!(f, "raw"),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum ColorType {
     Cmyk,
     GrayscaleAlpha,
@@ -50,12 +231,183 @@ enum ColorType {
     YCbCr,
 }
 
-#[derive(Debug)]
+impl ColorType {
+    fn channels(&self) -> usize {
+        use ColorType::*;
+
+        match self {
+            Grayscale => 1,
+            GrayscaleAlpha => 2,
+            Rgb | YCbCr => 3,
+            Rgba | Cmyk => 4,
+        }
+    }
+}
+
+/// Ancillary data carried alongside the pixels, when the source format provides it.
+#[derive(Debug, Clone, Default)]
+struct Metadata {
+    icc_profile: Option<Vec<u8>>,
+    /// Raw EXIF payload, starting at the TIFF header.
+    exif: Option<Vec<u8>>,
+    /// Orientation given by the container rather than EXIF (AVIF's irot/imir), as the
+    /// equivalent EXIF orientation value.
+    orientation: Option<u16>,
+    /// Width of each pixel relative to its height, when the source declares non-square
+    /// pixels.
+    pixel_aspect: Option<f64>,
+}
+
+impl Metadata {
+    /// The EXIF-style orientation (1-8) to apply for the image to display upright.
+    fn orientation(&self) -> Option<u16> {
+        self.orientation
+            .or_else(|| self.exif.as_deref().and_then(orient::exif_orientation))
+    }
+}
+
+/// The pixel aspect ratio implied by horizontal and vertical densities (pixels per unit),
+/// if the pixels aren't square.
+fn pixel_aspect(horizontal: f64, vertical: f64) -> Option<f64> {
+    let aspect = vertical / horizontal;
+
+    (aspect.is_finite() && aspect > 0. && aspect != 1.).then_some(aspect)
+}
+
+#[derive(Debug, Clone)]
 struct Decoded {
     bytes: Vec<u8>,
     color_type: ColorType,
     width: u32,
     height: u32,
+    /// Bits per sample, either 8 or 16. 16-bit samples are stored as native-endian `u16`s.
+    bit_depth: u8,
+    metadata: Metadata,
+}
+
+impl Decoded {
+    fn bytes_per_pixel(&self) -> usize {
+        self.color_type.channels() * self.bit_depth as usize / 8
+    }
+
+    /// The samples scaled to 8 bits, borrowing when they already are.
+    fn bytes_8bit(&self) -> Cow<'_, [u8]> {
+        match self.bit_depth {
+            16 => Cow::Owned(
+                self.bytes
+                    .chunks_exact(2)
+                    .map(|s| {
+                        let sample = u16::from_ne_bytes([s[0], s[1]]) as u32;
+
+                        ((sample * 255 + 32767) / 65535) as u8
+                    })
+                    .collect(),
+            ),
+            _ => Cow::Borrowed(&self.bytes),
+        }
+    }
+
+    /// Reduces 16-bit samples to 8 bits in place.
+    fn reduce_to_8bit(&mut self) {
+        if self.bit_depth != 8 {
+            self.bytes = self.bytes_8bit().into_owned();
+            self.bit_depth = 8;
+        }
+    }
+
+    /// Normalizes the decoded buffer into 8-bit RGBA pixels, regardless of source layout.
+    fn rgba_pixels(&self) -> Vec<RGBA8> {
+        use ColorType::*;
+
+        let channels = self.color_type.channels();
+
+        self.bytes_8bit()
+            .chunks_exact(channels)
+            .map(|px| match self.color_type {
+                Grayscale => RGBA8::new(px[0], px[0], px[0], 255),
+                GrayscaleAlpha => RGBA8::new(px[0], px[0], px[0], px[1]),
+                Rgb => RGBA8::new(px[0], px[1], px[2], 255),
+                Rgba => RGBA8::new(px[0], px[1], px[2], px[3]),
+                YCbCr => {
+                    let (y, cb, cr) = (px[0] as f32, px[1] as f32 - 128., px[2] as f32 - 128.);
+
+                    RGBA8::new(
+                        (y + 1.402 * cr).round().clamp(0., 255.) as u8,
+                        (y - 0.344136 * cb - 0.714136 * cr).round().clamp(0., 255.) as u8,
+                        (y + 1.772 * cb).round().clamp(0., 255.) as u8,
+                        255,
+                    )
+                }
+                Cmyk => {
+                    let k = 255 - px[3] as u16;
+                    let channel = |c: u8| ((255 - c as u16) * k / 255) as u8;
+
+                    RGBA8::new(channel(px[0]), channel(px[1]), channel(px[2]), 255)
+                }
+            })
+            .collect()
+    }
+}
+
+/// ravif's fastest speed setting, used as the fallback when a slower AVIF encode fails.
+const AVIF_FASTEST_SPEED: u8 = 10;
+
+/// Encoder knobs that aren't implied by the decoded image itself.
+#[derive(Debug)]
+struct EncodeOptions {
+    /// Quality (1-100) for the lossy JPEG and AVIF encoders.
+    quality: f32,
+    /// mozjpeg input smoothing factor (0-100), reducing noise before compression.
+    jpeg_smoothing: u8,
+    /// ravif speed (1-10); higher is faster but compresses worse.
+    avif_speed: u8,
+    /// Whether a failed AVIF encode is retried once at `AVIF_FASTEST_SPEED`.
+    avif_fallback: bool,
+    /// zlib effort for PNG image data.
+    png_compression: png::Compression,
+    /// Produce byte-identical output for identical input. AVIF tiling depends on the
+    /// thread count, so this encodes on a single thread. No encoder writes timestamps.
+    deterministic: bool,
+    /// ICC profile to embed in the output, for formats that support one.
+    icc_profile: Option<Vec<u8>>,
+    /// Write a JPEG restart marker every this many MCUs.
+    jpeg_restart_interval: Option<u16>,
+}
+
+impl EncodeOptions {
+    /// The instance's settings for `format`, for endpoints that don't take encoder
+    /// options of their own.
+    fn defaults(config: &config::Config, format: Format) -> Self {
+        EncodeOptions {
+            quality: config
+                .max_quality(&format)
+                .map_or(config.default_quality, |max| {
+                    config.default_quality.min(max)
+                }),
+            jpeg_smoothing: 0,
+            avif_speed: config.default_avif_speed,
+            avif_fallback: true,
+            png_compression: config.profile.png_compression(),
+            deterministic: false,
+            icc_profile: None,
+            jpeg_restart_interval: None,
+        }
+    }
+
+    /// Settings for small internal encodes, such as self-tests and placeholders, which
+    /// should be quick and reproducible rather than small.
+    fn quick(quality: f32) -> Self {
+        EncodeOptions {
+            quality,
+            jpeg_smoothing: 0,
+            avif_speed: AVIF_FASTEST_SPEED,
+            avif_fallback: false,
+            png_compression: png::Compression::Fast,
+            deterministic: true,
+            icc_profile: None,
+            jpeg_restart_interval: None,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -66,10 +418,121 @@ enum Error {
     UnsupportedColorType(Format, String),
     #[error("Could not get next frame")]
     NextFrameNotFound,
+    #[error("{0} image is {1}x{2}, over the limit of {3} pixels")]
+    TooManyPixels(Format, u32, u32, u64),
+    #[error("Page {0} is out of range, the image has {1} page(s)")]
+    PageOutOfRange(u32, u32),
+    #[error("{0} image is {1}x{2}, which has no pixels")]
+    Empty(Format, u32, u32),
+    #[error("{0} is an output-only format")]
+    OutputOnly(Format),
+}
+
+/// Rejects images with no pixels or more than `max_pixels`, ideally before their pixels
+/// are allocated.
+fn ensure_valid_dimensions(
+    format: Format,
+    width: u32,
+    height: u32,
+    max_pixels: u64,
+) -> anyhow::Result<()> {
+    if width == 0 || height == 0 {
+        bail!(Error::Empty(format, width, height));
+    }
+
+    if width as u64 * height as u64 > max_pixels {
+        bail!(Error::TooManyPixels(format, width, height, max_pixels));
+    }
+
+    Ok(())
+}
+
+/// Encodes a baseline JPEG with a restart marker every `interval` MCUs. mozjpeg's bindings
+/// don't expose DRI, so this goes through jpeg-encoder instead, without mozjpeg's trellis
+/// quantization or input smoothing; requests asking for smoothing too are refused.
+fn encode_jpeg_with_restarts(
+    image: &Decoded,
+    input: &[u8],
+    options: &EncodeOptions,
+    interval: u16,
+) -> anyhow::Result<Vec<u8>> {
+    let color_type = match image.color_type {
+        ColorType::Cmyk => jpeg_encoder::ColorType::Cmyk,
+        ColorType::Grayscale => jpeg_encoder::ColorType::Luma,
+        ColorType::Rgb => jpeg_encoder::ColorType::Rgb,
+        ColorType::Rgba => jpeg_encoder::ColorType::Rgba,
+        ColorType::YCbCr => jpeg_encoder::ColorType::Ycbcr,
+
+        c => bail!(Error::UnsupportedColorType(Format::Jpeg, format!("{c:?}"))),
+    };
+
+    let width = u16::try_from(image.width).context("JPEG: image is too wide")?;
+    let height = u16::try_from(image.height).context("JPEG: image is too tall")?;
+
+    let mut out = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut out, options.quality.round() as u8);
+
+    encoder.set_restart_interval(interval);
+
+    if let Some(icc) = &options.icc_profile {
+        encoder.add_icc_profile(icc)?;
+    }
+
+    encoder.encode(input, width, height, color_type)?;
+
+    Ok(out)
+}
+
+/// Counts the images (IFDs) in a TIFF, leaving the decoder on the last one.
+fn tiff_page_count<R: std::io::Read + Seek>(
+    decoder: &mut tiff::decoder::Decoder<R>,
+) -> tiff::TiffResult<u32> {
+    let mut pages = 1;
+
+    while decoder.more_images() {
+        decoder.next_image()?;
+        pages += 1;
+    }
+
+    Ok(pages)
+}
+
+/// Reorders native-endian 16-bit samples into the big-endian order that PNG and netpbm
+/// store them in.
+fn ne_to_be_16(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .chunks_exact(2)
+        .flat_map(|s| u16::from_ne_bytes([s[0], s[1]]).to_be_bytes())
+        .collect()
+}
+
+/// Reads big-endian 16-bit samples into the native-endian layout `Decoded` uses.
+fn be_to_ne_16(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .chunks_exact(2)
+        .flat_map(|s| u16::from_be_bytes([s[0], s[1]]).to_ne_bytes())
+        .collect()
 }
 
 impl Format {
-    fn decode(&mut self, mut input: impl BufRead + Seek) -> anyhow::Result<Decoded> {
+    /// Decodes an image, failing on empty ones and any over `max_pixels`. Formats whose
+    /// header can be read up front are checked before their pixel buffer is allocated.
+    fn decode(&mut self, input: impl BufRead + Seek, max_pixels: u64) -> anyhow::Result<Decoded> {
+        self.decode_page(input, max_pixels, 0)
+    }
+
+    /// Like `decode`, but takes the `page`th image of a multi-page TIFF. Every other format
+    /// has just the one page.
+    fn decode_page(
+        &mut self,
+        mut input: impl BufRead + Seek,
+        max_pixels: u64,
+        page: u32,
+    ) -> anyhow::Result<Decoded> {
+        if page > 0 && *self != Format::Tiff {
+            bail!(Error::PageOutOfRange(page, 1));
+        }
+
         match self {
             Format::Avif => {
                 use aom_decode::avif::Image::*;
@@ -78,7 +541,7 @@ impl Format {
 
                 input
                     .read_to_end(&mut buf)
-                    .expect("Failed to read AVIF file");
+                    .context("Failed to read AVIF file")?;
 
                 let mut decoder = aom_decode::avif::Avif::decode(
                     &buf,
@@ -86,90 +549,122 @@ impl Format {
                         threads: num_cpus::get(),
                     },
                 )
-                .expect("Could not read AVIF");
+                .context("Could not read AVIF")?;
+
+                let metadata = Metadata {
+                    orientation: orient::avif_orientation(&buf),
+                    pixel_aspect: orient::avif_pixel_aspect(&buf),
+                    ..Default::default()
+                };
 
-                match decoder.convert().expect("Failed to convert") {
+                let decoded = match decoder.convert().context("Failed to convert")? {
                     RGB8(img) => {
                         let (out, width, height) = img.into_contiguous_buf();
 
-                        Ok(Decoded {
+                        Decoded {
                             bytes: out.iter().flat_map(|x| [x.r, x.g, x.b]).collect(),
                             color_type: ColorType::Rgb,
                             width: width as u32,
                             height: height as u32,
-                        })
+                            bit_depth: 8,
+                            metadata,
+                        }
                     }
                     RGBA8(img) => {
                         let (out, width, height) = img.into_contiguous_buf();
 
-                        Ok(Decoded {
+                        Decoded {
                             bytes: out.iter().flat_map(|x| [x.r, x.g, x.b, x.a]).collect(),
                             color_type: ColorType::Rgba,
                             width: width as u32,
                             height: height as u32,
-                        })
+                            bit_depth: 8,
+                            metadata,
+                        }
                     }
                     Gray8(img) => {
                         let (out, width, height) = img.into_contiguous_buf();
 
-                        Ok(Decoded {
+                        Decoded {
                             bytes: out.to_vec(),
                             color_type: ColorType::Grayscale,
                             width: width as u32,
                             height: height as u32,
-                        })
+                            bit_depth: 8,
+                            metadata,
+                        }
                     }
+                    // 10- and 12-bit images come out scaled to the full 16-bit range, so
+                    // keep every bit.
                     RGB16(img) => {
-                        let mut out = Vec::new();
-
-                        for px in img.pixels() {
-                            out.push(px.map(|c| (c >> 8) as u8));
-                        }
+                        let (out, width, height) = img.into_contiguous_buf();
 
-                        Ok(Decoded {
-                            bytes: out.iter().flat_map(|x| [x.r, x.g, x.b]).collect(),
+                        Decoded {
+                            bytes: out
+                                .iter()
+                                .flat_map(|x| [x.r, x.g, x.b])
+                                .flat_map(u16::to_ne_bytes)
+                                .collect(),
                             color_type: ColorType::Rgb,
-                            width: img.width() as u32,
-                            height: img.height() as u32,
-                        })
+                            width: width as u32,
+                            height: height as u32,
+                            bit_depth: 16,
+                            metadata,
+                        }
                     }
                     RGBA16(img) => {
-                        let mut out = Vec::new();
-
-                        for px in img.pixels() {
-                            out.push(px.map(|c| (c >> 8) as u8));
-                        }
+                        let (out, width, height) = img.into_contiguous_buf();
 
-                        Ok(Decoded {
-                            bytes: out.iter().flat_map(|x| [x.r, x.g, x.b, x.a]).collect(),
+                        Decoded {
+                            bytes: out
+                                .iter()
+                                .flat_map(|x| [x.r, x.g, x.b, x.a])
+                                .flat_map(u16::to_ne_bytes)
+                                .collect(),
                             color_type: ColorType::Rgba,
-                            width: img.width() as u32,
-                            height: img.height() as u32,
-                        })
+                            width: width as u32,
+                            height: height as u32,
+                            bit_depth: 16,
+                            metadata,
+                        }
                     }
                     Gray16(img) => {
-                        let mut out = Vec::new();
-
-                        for px in img.pixels() {
-                            out.push((px >> 8) as u8);
-                        }
+                        let (out, width, height) = img.into_contiguous_buf();
 
-                        Ok(Decoded {
-                            bytes: out.to_vec(),
+                        Decoded {
+                            bytes: out.iter().flat_map(|x| x.to_ne_bytes()).collect(),
                             color_type: ColorType::Grayscale,
-                            width: img.width() as u32,
-                            height: img.height() as u32,
-                        })
+                            width: width as u32,
+                            height: height as u32,
+                            bit_depth: 16,
+                            metadata,
+                        }
                     }
-                }
+                };
+
+                // aom-decode only reports dimensions once the image is decoded.
+                ensure_valid_dimensions(Format::Avif, decoded.width, decoded.height, max_pixels)?;
+
+                Ok(decoded)
             }
             Format::Png => {
-                let decoder = png::Decoder::new(&mut input);
+                let mut decoder = png::Decoder::new(&mut input);
+
+                // Expands palette images to RGB (or RGBA when there's a tRNS chunk) and
+                // sub-byte grayscale to 8 bits, so the buffer matches one of our color types.
+                decoder.set_transformations(png::Transformations::EXPAND);
 
                 let mut reader = decoder
                     .read_info()
                     .context(Error::CouldNotReadInfo(Format::Png))?;
 
+                ensure_valid_dimensions(
+                    Format::Png,
+                    reader.info().width,
+                    reader.info().height,
+                    max_pixels,
+                )?;
+
                 let mut out = vec![0; reader.output_buffer_size()];
 
                 let info = reader
@@ -181,7 +676,9 @@ impl Format {
                 let width = reader.info().width;
                 let height = reader.info().height;
 
-                let color_type = match reader.info().color_type {
+                let (png_color_type, png_bit_depth) = reader.output_color_type();
+
+                let color_type = match png_color_type {
                     png::ColorType::Grayscale => ColorType::Grayscale,
                     png::ColorType::GrayscaleAlpha => ColorType::GrayscaleAlpha,
                     png::ColorType::Rgb => ColorType::Rgb,
@@ -190,91 +687,306 @@ impl Format {
                     c => bail!(Error::UnsupportedColorType(Format::Png, format!("{c:?}"))),
                 };
 
+                let metadata = Metadata {
+                    icc_profile: reader.info().icc_profile.as_ref().map(|p| p.to_vec()),
+                    exif: None,
+                    orientation: None,
+                    pixel_aspect: reader
+                        .info()
+                        .pixel_dims
+                        .and_then(|dims| pixel_aspect(dims.xppu as f64, dims.yppu as f64)),
+                };
+
+                // PNG stores 16-bit samples big-endian; keep them native-endian internally.
+                let (bytes, bit_depth) = match png_bit_depth {
+                    png::BitDepth::Sixteen => (be_to_ne_16(bytes), 16),
+                    _ => (bytes.to_vec(), 8),
+                };
+
                 Ok(Decoded {
-                    bytes: bytes.to_vec(),
+                    bytes,
                     color_type,
                     width,
                     height,
+                    bit_depth,
+                    metadata,
                 })
             }
             Format::Jpeg => {
                 let decoder = mozjpeg::Decompress::builder()
+                    .with_markers(&[
+                        mozjpeg::Marker::APP(0),
+                        mozjpeg::Marker::APP(1),
+                        mozjpeg::Marker::APP(2),
+                        mozjpeg::Marker::APP(14),
+                    ])
                     .from_reader(&mut input)
-                    .expect("Could not build JPEG decompressor");
+                    .context("Could not build JPEG decompressor")?;
 
+                // The header's been read by now, so bombs are caught before any scanlines.
                 let width = decoder.width() as u32;
                 let height = decoder.height() as u32;
-                let color_space = decoder.color_space();
 
-                let color_type = match color_space {
-                    mozjpeg::ColorSpace::JCS_GRAYSCALE => ColorType::Grayscale,
-                    mozjpeg::ColorSpace::JCS_RGB => ColorType::Rgb,
-                    mozjpeg::ColorSpace::JCS_YCbCr => ColorType::YCbCr,
-                    mozjpeg::ColorSpace::JCS_CMYK => ColorType::Cmyk,
+                ensure_valid_dimensions(Format::Jpeg, width, height, max_pixels)?;
+                let color_space = decoder.color_space();
+                let metadata = jpeg_metadata(&decoder);
+
+                let components = decoder.components().len();
+                // Adobe's writers, which flag themselves with an APP14 marker, store CMYK
+                // (and so YCCK) inverted.
+                let adobe = decoder.markers().any(|marker| {
+                    marker.marker == mozjpeg::Marker::APP(14) && marker.data.starts_with(b"Adobe")
+                });
+
+                let (out_color_space, color_type) = match color_space {
+                    mozjpeg::ColorSpace::JCS_GRAYSCALE => (color_space, ColorType::Grayscale),
+                    mozjpeg::ColorSpace::JCS_RGB => (color_space, ColorType::Rgb),
+                    mozjpeg::ColorSpace::JCS_YCbCr => (color_space, ColorType::YCbCr),
+                    mozjpeg::ColorSpace::JCS_CMYK => (color_space, ColorType::Cmyk),
+                    // libjpeg converts YCCK back to CMYK itself.
+                    mozjpeg::ColorSpace::JCS_YCCK => {
+                        (mozjpeg::ColorSpace::JCS_CMYK, ColorType::Cmyk)
+                    }
+                    // With no declared color space, go by the component count. Five is
+                    // most plausibly CMYK plus alpha, split off below.
+                    mozjpeg::ColorSpace::JCS_UNKNOWN => match components {
+                        1 => (color_space, ColorType::Grayscale),
+                        2 => (color_space, ColorType::GrayscaleAlpha),
+                        3 => (color_space, ColorType::Rgb),
+                        4 | 5 => (color_space, ColorType::Cmyk),
+
+                        n => bail!(Error::UnsupportedColorType(
+                            Format::Jpeg,
+                            format!("{n} unlabelled components")
+                        )),
+                    },
 
                     e => bail!(Error::UnsupportedColorType(Format::Jpeg, format!("{e:?}"))),
                 };
 
-                let mut pixels = decoder.to_colorspace(color_space).expect("weh");
+                let mut pixels = decoder
+                    .to_colorspace(out_color_space)
+                    .context("Could not start JPEG decompression")?;
 
-                let bytes = pixels
+                let mut bytes: Vec<u8> = pixels
                     .read_scanlines()
-                    .expect("Could not read JPEG scanlines");
+                    .context("Could not read JPEG scanlines")?;
+
+                if adobe && out_color_space == mozjpeg::ColorSpace::JCS_CMYK {
+                    bytes.iter_mut().for_each(|sample| *sample = 255 - *sample);
+                }
 
                 pixels
                     .finish()
-                    .expect("Could not finish JPEG decompression");
+                    .context("Could not finish JPEG decompression")?;
+
+                if color_space == mozjpeg::ColorSpace::JCS_UNKNOWN && components == 5 {
+                    let cmyk = Decoded {
+                        bytes: bytes
+                            .chunks_exact(5)
+                            .flat_map(|px| [px[0], px[1], px[2], px[3]])
+                            .collect(),
+                        color_type: ColorType::Cmyk,
+                        width,
+                        height,
+                        bit_depth: 8,
+                        metadata: Metadata::default(),
+                    };
+
+                    return Ok(Decoded {
+                        bytes: cmyk
+                            .rgba_pixels()
+                            .iter()
+                            .zip(bytes.chunks_exact(5))
+                            .flat_map(|(px, cmyka)| [px.r, px.g, px.b, cmyka[4]])
+                            .collect(),
+                        color_type: ColorType::Rgba,
+                        metadata,
+                        ..cmyk
+                    });
+                }
 
                 Ok(Decoded {
                     bytes,
                     color_type,
                     width,
                     height,
+                    bit_depth: 8,
+                    metadata,
                 })
             }
             Format::WebP => {
                 let mut decoder =
-                    image_webp::WebPDecoder::new(&mut input).expect("WebP: failed on new");
+                    image_webp::WebPDecoder::new(&mut input).context("WebP: failed on new")?;
+
+                let (width, height) = decoder.dimensions();
+                ensure_valid_dimensions(Format::WebP, width, height, max_pixels)?;
 
                 let mut out = vec![
                     0;
                     decoder
                         .output_buffer_size()
-                        .expect("WebP: failed to get buffer size")
+                        .context("WebP: failed to get buffer size")?
                 ];
 
-                let (width, height) = decoder.dimensions();
                 let color_type = match decoder.has_alpha() {
                     true => ColorType::Rgba,
                     false => ColorType::Rgb,
                 };
 
-                decoder
-                    .read_image(&mut out)
-                    .expect("WebP: failed on read_image");
+                // Extended (VP8X) files flag animation, alpha and ICC/EXIF/XMP chunks.
+                // Animations are only split apart by `/frames`; here they convert as their
+                // first frame, composited onto the canvas at its full size.
+                match decoder.is_animated() {
+                    true => {
+                        decoder
+                            .read_frame(&mut out)
+                            .context("WebP: failed to read the first frame")?;
+                    }
+                    false => decoder
+                        .read_image(&mut out)
+                        .context("WebP: failed on read_image")?,
+                }
+
+                // Some writers keep JPEG's APP1 prefix in the EXIF chunk.
+                let exif =
+                    decoder
+                        .exif_metadata()?
+                        .map(|exif| match exif.strip_prefix(b"Exif\0\0") {
+                            Some(tiff) => tiff.to_vec(),
+                            None => exif,
+                        });
 
                 Ok(Decoded {
                     bytes: out,
                     color_type,
                     width,
                     height,
+                    bit_depth: 8,
+                    metadata: Metadata {
+                        icc_profile: decoder.icc_profile()?,
+                        exif,
+                        ..Default::default()
+                    },
+                })
+            }
+            Format::Pnm => {
+                let mut buf = Vec::new();
+                input.read_to_end(&mut buf)?;
+
+                pnm::decode(&buf, max_pixels)
+            }
+            Format::Tiff => {
+                use tiff::{decoder::DecodingResult, ColorType::*};
+
+                let mut decoder = tiff::decoder::Decoder::new(input)
+                    .context(Error::CouldNotReadInfo(Format::Tiff))?;
+
+                if page > 0 {
+                    let pages = tiff_page_count(&mut decoder)?;
+
+                    if page >= pages {
+                        bail!(Error::PageOutOfRange(page, pages));
+                    }
+
+                    decoder.seek_to_image(page as usize)?;
+                }
+
+                let (width, height) = decoder.dimensions()?;
+                ensure_valid_dimensions(Format::Tiff, width, height, max_pixels)?;
+
+                let color_type = match decoder.colortype()? {
+                    Gray(8 | 16) => ColorType::Grayscale,
+                    GrayA(8 | 16) => ColorType::GrayscaleAlpha,
+                    RGB(8 | 16) => ColorType::Rgb,
+                    RGBA(8 | 16) => ColorType::Rgba,
+                    CMYK(8 | 16) => ColorType::Cmyk,
+
+                    c => bail!(Error::UnsupportedColorType(Format::Tiff, format!("{c:?}"))),
+                };
+
+                let (bytes, bit_depth) = match decoder.read_image()? {
+                    DecodingResult::U8(samples) => (samples, 8),
+                    DecodingResult::U16(samples) => {
+                        (samples.iter().flat_map(|s| s.to_ne_bytes()).collect(), 16)
+                    }
+
+                    _ => bail!(Error::UnsupportedColorType(
+                        Format::Tiff,
+                        "non-integer samples".to_string()
+                    )),
+                };
+
+                let mut resolution = |tag| match decoder.find_tag(tag) {
+                    Ok(Some(tiff::decoder::ifd::Value::Rational(n, d))) if d != 0 => {
+                        Some(n as f64 / d as f64)
+                    }
+                    _ => None,
+                };
+
+                let metadata = Metadata {
+                    pixel_aspect: resolution(tiff::tags::Tag::XResolution)
+                        .zip(resolution(tiff::tags::Tag::YResolution))
+                        .and_then(|(x, y)| pixel_aspect(x, y)),
+                    ..Default::default()
+                };
+
+                Ok(Decoded {
+                    bytes,
+                    color_type,
+                    width,
+                    height,
+                    bit_depth,
+                    metadata,
                 })
             }
+            Format::Raw => bail!(Error::OutputOnly(Format::Raw)),
         }
     }
 
-    fn encode(&mut self, input: &[u8], width: u32, height: u32, color_type: ColorType) -> Vec<u8> {
+    fn encode(&mut self, image: &Decoded, options: &EncodeOptions) -> anyhow::Result<Vec<u8>> {
+        let _charge = budget::Charge::start();
+
+        let Decoded {
+            color_type,
+            width,
+            height,
+            ..
+        } = image;
+        let (width, height) = (*width, *height);
+
+        // Only PNG and TIFF can carry 16-bit samples; everything else gets them scaled down.
+        let input = &image.bytes_8bit();
+
         let mut out = Vec::new();
 
         match self {
             Format::Avif => {
-                let encoder = ravif::Encoder::new()
-                    .with_quality(95.)
-                    .with_speed(10)
-                    .encode_rgba(Img::new(input.as_rgba(), width as usize, height as usize))
-                    .expect("Failed to encode to AVIF");
+                // ravif only takes RGBA, and drops the alpha channel itself when it's opaque.
+                let pixels = image.rgba_pixels();
+
+                let encode = |speed| {
+                    ravif::Encoder::new()
+                        .with_quality(options.quality)
+                        .with_speed(speed)
+                        .with_num_threads(options.deterministic.then_some(1))
+                        .encode_rgba(Img::new(&pixels[..], width as usize, height as usize))
+                };
 
-                encoder.avif_file
+                let encoded = match encode(options.avif_speed) {
+                    Ok(encoded) => encoded,
+                    Err(e) if options.avif_fallback && options.avif_speed < AVIF_FASTEST_SPEED => {
+                        log::warn!(
+                            "AVIF encode failed at speed {} ({e}), retrying at speed {AVIF_FASTEST_SPEED}",
+                            options.avif_speed
+                        );
+
+                        encode(AVIF_FASTEST_SPEED)?
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+
+                Ok(encoded.avif_file)
             }
             Format::Png => {
                 let mut encoder = png::Encoder::new(&mut out, width, height);
@@ -285,16 +997,37 @@ impl Format {
                     ColorType::Rgb => png::ColorType::Rgb,
                     ColorType::Rgba => png::ColorType::Rgba,
 
-                    c => panic!("PNG: unsupported color type: {:?}", c),
+                    c => bail!(Error::UnsupportedColorType(Format::Png, format!("{c:?}"))),
                 };
 
                 encoder.set_color(png_color_type);
+                encoder.set_compression(options.png_compression);
+
+                let writer = match image.bit_depth {
+                    16 => {
+                        encoder.set_depth(png::BitDepth::Sixteen);
+
+                        let mut writer = encoder.write_header()?;
+                        write_png_icc(&mut writer, options)?;
+
+                        // Copying the buffer as-is would swap every sample's bytes on
+                        // little-endian hosts.
+                        writer.write_image_data(&ne_to_be_16(&image.bytes))?;
+                        writer
+                    }
+                    _ => {
+                        encoder.set_depth(png::BitDepth::Eight);
+
+                        let mut writer = encoder.write_header()?;
+                        write_png_icc(&mut writer, options)?;
+                        writer.write_image_data(input)?;
+                        writer
+                    }
+                };
 
-                let mut writer = encoder.write_header().unwrap();
-                writer.write_image_data(input).unwrap();
-                writer.finish().unwrap();
+                writer.finish()?;
 
-                out
+                Ok(out)
             }
             Format::Jpeg => {
                 let color_space = match color_type {
@@ -303,106 +1036,1686 @@ impl Format {
                     ColorType::Rgb => mozjpeg::ColorSpace::JCS_RGB,
                     ColorType::Rgba => mozjpeg::ColorSpace::JCS_EXT_RGBA,
                     ColorType::YCbCr => mozjpeg::ColorSpace::JCS_YCbCr,
-                    c => panic!("Unsupported color type: {:?}", c),
+
+                    c => bail!(Error::UnsupportedColorType(Format::Jpeg, format!("{c:?}"))),
+                };
+
+                if let Some(interval) = options.jpeg_restart_interval {
+                    return encode_jpeg_with_restarts(image, input, options, interval);
+                }
+
+                // libjpeg flags CMYK with an Adobe marker but, unlike Adobe's writers and
+                // jpeg-encoder, doesn't invert it.
+                let inverted: Vec<u8>;
+                let input: &[u8] = match color_type {
+                    ColorType::Cmyk => {
+                        inverted = input.iter().map(|sample| 255 - sample).collect();
+                        &inverted
+                    }
+                    _ => input,
                 };
 
                 let mut encoder = mozjpeg::Compress::new(color_space);
 
-                encoder.set_quality(95.0);
+                encoder.set_quality(options.quality);
+                encoder.set_smoothing_factor(options.jpeg_smoothing);
                 encoder.set_size(width as usize, height as usize);
 
                 let mut comp = encoder
                     .start_compress(out)
-                    .expect("JPEG: failed on start_compress");
+                    .context("JPEG: failed on start_compress")?;
+
+                if let Some(icc) = &options.icc_profile {
+                    comp.write_icc_profile(icc);
+                }
 
                 comp.write_scanlines(input)
-                    .expect("Failed on write_scanlines");
+                    .context("JPEG: failed on write_scanlines")?;
 
-                comp.finish().expect("Failed on finish")
+                Ok(comp.finish().context("JPEG: failed on finish")?)
             }
             Format::WebP => {
-                let encoder = image_webp::WebPEncoder::new(&mut out);
+                let mut encoder = image_webp::WebPEncoder::new(&mut out);
+
+                if let Some(icc) = &options.icc_profile {
+                    encoder.set_icc_profile(icc.clone());
+                }
 
                 let webp_color_type = match color_type {
                     ColorType::Grayscale => image_webp::ColorType::L8,
                     ColorType::GrayscaleAlpha => image_webp::ColorType::La8,
                     ColorType::Rgb => image_webp::ColorType::Rgb8,
                     ColorType::Rgba => image_webp::ColorType::Rgba8,
-                    _ => panic!("Unsupported color type"),
+
+                    c => bail!(Error::UnsupportedColorType(Format::WebP, format!("{c:?}"))),
+                };
+
+                encoder.encode(input, width, height, webp_color_type)?;
+
+                Ok(out)
+            }
+            Format::Pnm => pnm::encode(image),
+            // Big-endian like the other formats' 16-bit samples, so clients needn't know
+            // the server's byte order.
+            Format::Raw => Ok(match image.bit_depth {
+                16 => ne_to_be_16(&image.bytes),
+                _ => image.bytes.clone(),
+            }),
+            Format::Tiff => {
+                use tiff::encoder::{colortype, TiffEncoder};
+
+                let mut encoder = TiffEncoder::new(Cursor::new(&mut out))?;
+
+                let wide = || -> Vec<u16> {
+                    image
+                        .bytes
+                        .chunks_exact(2)
+                        .map(|s| u16::from_ne_bytes([s[0], s[1]]))
+                        .collect()
                 };
 
-                encoder
-                    .encode(input, width, height, webp_color_type)
-                    .unwrap();
+                match (color_type, image.bit_depth) {
+                    (ColorType::Grayscale, 16) => {
+                        encoder.write_image::<colortype::Gray16>(width, height, &wide())
+                    }
+                    (ColorType::Grayscale, _) => {
+                        encoder.write_image::<colortype::Gray8>(width, height, input)
+                    }
+                    (ColorType::Rgb, 16) => {
+                        encoder.write_image::<colortype::RGB16>(width, height, &wide())
+                    }
+                    (ColorType::Rgb, _) => {
+                        encoder.write_image::<colortype::RGB8>(width, height, input)
+                    }
+                    (ColorType::Rgba, 16) => {
+                        encoder.write_image::<colortype::RGBA16>(width, height, &wide())
+                    }
+                    (ColorType::Rgba, _) => {
+                        encoder.write_image::<colortype::RGBA8>(width, height, input)
+                    }
+                    (ColorType::Cmyk, 16) => {
+                        encoder.write_image::<colortype::CMYK16>(width, height, &wide())
+                    }
+                    (ColorType::Cmyk, _) => {
+                        encoder.write_image::<colortype::CMYK8>(width, height, input)
+                    }
+
+                    (c, _) => bail!(Error::UnsupportedColorType(Format::Tiff, format!("{c:?}"))),
+                }?;
 
-                out
+                Ok(out)
             }
         }
     }
 }
 
-#[post("/convert_image")]
-async fn convert_image(
-    MultipartForm(UploadForm {
-        file: input,
-        output_type,
-    }): MultipartForm<UploadForm>,
-) -> actix_web::Result<impl Responder, actix_web::Error> {
-    let file = std::io::BufReader::new(input.file.into_file());
+/// Writes `options.icc_profile`, if any, as a PNG iCCP chunk. Must come before the image
+/// data.
+fn write_png_icc<W: Write>(
+    writer: &mut png::Writer<W>,
+    options: &EncodeOptions,
+) -> anyhow::Result<()> {
+    let Some(icc) = &options.icc_profile else {
+        return Ok(());
+    };
 
-    let decoded = match input.content_type.clone().unwrap().subtype().as_str() {
-        "avif" => Format::Avif.decode(file),
-        "png" => Format::Png.decode(file),
-        "jpeg" => Format::Jpeg.decode(file),
-        "webp" => Format::WebP.decode(file),
+    // Profile name, then compression method 0 (zlib) and the compressed profile.
+    let mut chunk = b"ICC Profile\0\0".to_vec();
 
-        _ => return Ok(HttpResponse::BadRequest().body("Unsupported input type")),
-    };
+    let mut zlib = flate2::write::ZlibEncoder::new(&mut chunk, flate2::Compression::default());
+    zlib.write_all(icc)?;
+    zlib.finish()?;
 
-    let Decoded {
-        bytes,
-        color_type,
-        width,
-        height,
-    } = decoded.unwrap();
+    writer.write_chunk(png::chunk::iCCP, &chunk)?;
+
+    Ok(())
+}
+
+/// Collects the JFIF density (APP0), EXIF (APP1) and ICC profile (APP2, possibly split
+/// across several segments) markers saved by the JPEG decompressor.
+fn jpeg_metadata<R>(decoder: &mozjpeg::Decompress<R>) -> Metadata {
+    const JFIF_HEADER: &[u8] = b"JFIF\0";
+    const EXIF_HEADER: &[u8] = b"Exif\0\0";
+    const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+
+    let mut exif = None;
+    let mut icc_chunks = Vec::new();
+    let mut aspect = None;
+
+    for marker in decoder.markers() {
+        match marker.marker {
+            // After the version and units come the X and Y densities, which give the pixel
+            // aspect ratio even when the units are unspecified.
+            mozjpeg::Marker::APP(0)
+                if marker.data.starts_with(JFIF_HEADER) && marker.data.len() >= 12 =>
+            {
+                let density =
+                    |at: usize| u16::from_be_bytes([marker.data[at], marker.data[at + 1]]);
+
+                aspect = pixel_aspect(density(8) as f64, density(10) as f64);
+            }
+            mozjpeg::Marker::APP(1) if marker.data.starts_with(EXIF_HEADER) => {
+                exif = Some(marker.data[EXIF_HEADER.len()..].to_vec());
+            }
+            // Each ICC segment is prefixed with its 1-based sequence number and the total count.
+            mozjpeg::Marker::APP(2)
+                if marker.data.starts_with(ICC_HEADER)
+                    && marker.data.len() > ICC_HEADER.len() + 2 =>
+            {
+                let seq = marker.data[ICC_HEADER.len()];
+
+                icc_chunks.push((seq, &marker.data[ICC_HEADER.len() + 2..]));
+            }
+            _ => {}
+        }
+    }
+
+    icc_chunks.sort_by_key(|(seq, _)| *seq);
+
+    Metadata {
+        icc_profile: (!icc_chunks.is_empty()).then(|| {
+            icc_chunks
+                .into_iter()
+                .flat_map(|(_, chunk)| chunk.to_vec())
+                .collect()
+        }),
+        exif,
+        orientation: None,
+        pixel_aspect: aspect,
+    }
+}
+
+/// Decodes an uploaded file according to its content type, or produces the error
+/// response to send back if the type isn't supported.
+fn decode_upload(input: TempFile, config: &config::Config) -> Result<Decoded, HttpResponse> {
+    decode_upload_page(input, config, 0)
+}
 
-    let out = match output_type.as_str() {
-        "avif" => Format::Avif.encode(&bytes, width, height, color_type),
-        "png" => Format::Png.encode(&bytes, width, height, color_type),
-        "jpeg" => Format::Jpeg.encode(&bytes, width, height, color_type),
-        "webp" => Format::WebP.encode(&bytes, width, height, color_type),
+/// Like `decode_upload`, but picks a page of a multi-page TIFF, answering 400 for pages
+/// the file doesn't have.
+fn decode_upload_page(
+    input: TempFile,
+    config: &config::Config,
+    page: u32,
+) -> Result<Decoded, HttpResponse> {
+    let Some(mut format) = upload_format(&input) else {
+        if is_svg(&input) {
+            return Err(HttpResponse::UnsupportedMediaType()
+                .body("SVG isn't accepted, since it can carry script"));
+        }
 
-        _ => return Ok(HttpResponse::BadRequest().body("Unsupported output type")),
+        return Err(HttpResponse::BadRequest().body("Unsupported input type"));
     };
 
-    Ok(HttpResponse::Ok()
-        .content_type(match output_type.to_lowercase().as_str() {
-            "bmp" => "image/bmp",
-            "gif" => "image/gif",
-            "ico" => "image/x-icon",
-            "jpeg" => "image/jpeg",
-            "pbm" | "pgm" | "ppm" | "pam" => "image/x-portable-anymap",
-            "png" => "image/png",
-            "tga" => "image/x-tga",
-            "tiff" => "image/tiff",
-            "webp" => "image/webp",
-            _ => "application/octet-stream",
+    if !config.allowed_input_formats.contains(format) {
+        return Err(disallowed_input(format));
+    }
+
+    let file = std::io::BufReader::new(input.file.into_file());
+
+    format
+        .decode_page(file, config.max_input_pixels, page)
+        .map_err(|e| match e.downcast_ref::<Error>() {
+            Some(e @ Error::PageOutOfRange(..)) => HttpResponse::BadRequest().body(e.to_string()),
+            _ => HttpResponse::UnprocessableEntity().body(format!("{e:#}")),
         })
-        .body(out))
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allowed_methods(vec!["POST"])
-            .allowed_header(actix_web::http::header::CONTENT_TYPE)
-            .max_age(3600);
+/// The 403 for uploads in a format the instance doesn't accept (`ALLOWED_INPUT_FORMATS`).
+fn disallowed_input(format: Format) -> HttpResponse {
+    HttpResponse::Forbidden().body(format!("{format} input isn't allowed on this instance"))
+}
+
+/// The 403 for outputs the instance doesn't produce (`ALLOWED_OUTPUT_FORMATS`).
+fn disallowed_output(format: Format) -> HttpResponse {
+    HttpResponse::Forbidden().body(format!("{format} output isn't allowed on this instance"))
+}
+
+/// Formats the instance currently accepts and produces.
+#[derive(Debug, Serialize)]
+struct FormatsView {
+    inputs: Vec<&'static str>,
+    outputs: Vec<&'static str>,
+}
 
-        App::new().wrap(cors).service(convert_image)
+async fn formats(config: web::Data<config::Config>) -> impl Responder {
+    HttpResponse::Ok().json(FormatsView {
+        inputs: config.allowed_input_formats.names(),
+        outputs: config.allowed_output_formats.names(),
     })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+}
+
+/// The uploaded file's name without its extension, made safe to reuse in output names.
+fn upload_stem(input: &TempFile) -> String {
+    input
+        .file_name
+        .as_deref()
+        .map(|name| name.rsplit_once('.').map_or(name, |(stem, _)| stem))
+        .map(|stem| stem.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_"))
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or_else(|| "image".to_string())
+}
+
+impl Format {
+    /// Whether `buf` starts with this format's magic bytes.
+    fn matches_signature(&self, buf: &[u8]) -> bool {
+        match self {
+            Format::Avif => {
+                buf.get(4..8) == Some(b"ftyp")
+                    && matches!(buf.get(8..12), Some(b"avif" | b"avis" | b"mif1"))
+            }
+            Format::Png => buf.starts_with(b"\x89PNG\r\n\x1a\n"),
+            Format::Jpeg => buf.starts_with(&[0xff, 0xd8, 0xff]),
+            Format::WebP => buf.starts_with(b"RIFF") && buf.get(8..12) == Some(b"WEBP"),
+            Format::Tiff => buf.starts_with(b"II*\0") || buf.starts_with(b"MM\0*"),
+            Format::Pnm => matches!(buf, [b'P', b'1'..=b'6', ..]),
+            Format::Raw => false,
+        }
+    }
+}
+
+/// Whether an upload is declared as SVG, which we refuse outright: it's a document that
+/// can run script, not a raster image.
+fn is_svg(input: &TempFile) -> bool {
+    input
+        .content_type
+        .as_ref()
+        .is_some_and(|mime| mime.subtype() == mime::SVG)
+}
+
+/// The format of an uploaded file, going by its declared content type.
+fn upload_format(input: &TempFile) -> Option<Format> {
+    match input.content_type.as_ref()?.subtype().as_str() {
+        "avif" => Some(Format::Avif),
+        "png" => Some(Format::Png),
+        "jpeg" => Some(Format::Jpeg),
+        "webp" => Some(Format::WebP),
+        "tiff" => Some(Format::Tiff),
+        "x-portable-anymap" | "x-portable-bitmap" | "x-portable-graymap" | "x-portable-pixmap" => {
+            Some(Format::Pnm)
+        }
+
+        _ => None,
+    }
+}
+
+/// The format named by a request's `output_type`.
+fn output_format(name: &str) -> Option<Format> {
+    match name {
+        "avif" => Some(Format::Avif),
+        "png" => Some(Format::Png),
+        "jpeg" => Some(Format::Jpeg),
+        "webp" => Some(Format::WebP),
+        "pnm" => Some(Format::Pnm),
+        "tiff" => Some(Format::Tiff),
+
+        _ => None,
+    }
+}
+
+async fn convert_image(
+    req: HttpRequest,
+    config: web::Data<config::Config>,
+    cache: web::Data<cache::Cache>,
+    MultipartForm(UploadForm {
+        file: input,
+        output_type,
+        autocrop,
+        autocrop_tolerance,
+        jpeg_smoothing,
+        jpeg_restart_interval,
+        quality,
+        auto_orient,
+        orient_mode,
+        to_srgb,
+        auto_levels,
+        auto_levels_clip,
+        auto_levels_mode,
+        max_dimension,
+        web_ready,
+        avif_speed,
+        avif_fallback,
+        scale,
+        deterministic,
+        output_bit_depth,
+        assign_profile,
+        width,
+        height,
+        fit,
+        background,
+        correct_pixel_aspect,
+        page,
+        with_lqip,
+        output_color_type,
+        strict_fidelity,
+        extract,
+    }): MultipartForm<UploadForm>,
+) -> actix_web::Result<impl Responder, actix_web::Error> {
+    // Anything that could change the pixels or the encoder's settings rules out passing
+    // the upload through untouched.
+    let has_transforms = autocrop.is_some()
+        || jpeg_smoothing.is_some()
+        || jpeg_restart_interval.is_some()
+        || quality.is_some()
+        || avif_speed.is_some()
+        || auto_orient.is_some()
+        || orient_mode.is_some()
+        || to_srgb.is_some()
+        || auto_levels.is_some()
+        || max_dimension.is_some()
+        || web_ready.is_some()
+        || scale.is_some()
+        || output_bit_depth.is_some()
+        || assign_profile.is_some()
+        || width.is_some()
+        || height.is_some()
+        || correct_pixel_aspect.is_some()
+        || page.is_some()
+        || with_lqip.is_some()
+        || output_color_type.is_some()
+        || extract.is_some();
+
+    let explicit_quality = quality.is_some();
+
+    let mut options = EncodeOptions {
+        quality: quality.map_or(config.default_quality, |q| *q),
+        avif_speed: avif_speed.map_or(config.default_avif_speed, |s| *s),
+        png_compression: config.profile.png_compression(),
+        jpeg_smoothing: jpeg_smoothing.map_or(0, |s| *s),
+        jpeg_restart_interval: jpeg_restart_interval.map(|i| *i),
+        avif_fallback: avif_fallback.is_none_or(|f| *f),
+        deterministic: deterministic.is_some_and(|d| *d),
+        icc_profile: None,
+    };
+
+    if !(1. ..=100.).contains(&options.quality) {
+        return Ok(HttpResponse::BadRequest().body("quality must be between 1 and 100"));
+    }
+
+    if !(1..=AVIF_FASTEST_SPEED).contains(&options.avif_speed) {
+        return Ok(HttpResponse::BadRequest().body("avif_speed must be between 1 and 10"));
+    }
+
+    if options.jpeg_smoothing > 100 {
+        return Ok(HttpResponse::BadRequest().body("jpeg_smoothing must be between 0 and 100"));
+    }
+
+    let scale = scale.map(|s| *s);
+    let page = page.map_or(0, |p| *p);
+    let with_lqip = with_lqip.is_some_and(|l| *l);
+    let correct_pixel_aspect = correct_pixel_aspect.is_some_and(|c| *c);
+    let strict_fidelity = strict_fidelity.is_some_and(|s| *s);
+
+    if scale.is_some_and(|s| !(s > 0. && s.is_finite())) {
+        return Ok(HttpResponse::BadRequest().body("scale must be positive"));
+    }
+
+    let optimize = output_type.is_none();
+
+    let output_type = match output_type {
+        Some(output_type) => output_type.into_inner(),
+        None => match upload_format(&input) {
+            Some(format) => format.name().to_string(),
+            None => {
+                return Ok(HttpResponse::BadRequest()
+                    .body("output_type is required unless the upload is a supported image"))
+            }
+        },
+    };
+
+    if optimize {
+        options.png_compression = png::Compression::Best;
+    }
+
+    // Only this endpoint can describe a raw buffer's layout, in its response headers.
+    let Some(mut format) = Format::from_name(&output_type) else {
+        return Ok(HttpResponse::BadRequest().body("Unsupported output type"));
+    };
+
+    if !config.allowed_output_formats.contains(format) {
+        return Ok(disallowed_output(format));
+    }
+
+    // Checked here as well as at decode, since passthrough and cache hits skip decoding.
+    if let Some(input_format) = upload_format(&input) {
+        if !config.allowed_input_formats.contains(input_format) {
+            return Ok(disallowed_input(input_format));
+        }
+    }
+
+    let content_type = match output_type.to_lowercase().as_str() {
+        "bmp" => "image/bmp",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "jpeg" => "image/jpeg",
+        "pbm" | "pgm" | "ppm" | "pam" | "pnm" => "image/x-portable-anymap",
+        "png" => "image/png",
+        "tga" => "image/x-tga",
+        "tiff" => "image/tiff",
+        "webp" => "image/webp",
+        _ => "application/octet-stream",
+    };
+
+    // Only the lossy encoders take a quality, and the instance may cap it.
+    let applied_quality = config
+        .max_quality(&format)
+        .map(|max| options.quality.min(max));
+
+    if let Some(quality) = applied_quality {
+        options.quality = quality;
+    }
+
+    // Likewise the AVIF encoder's effort.
+    let applied_avif_speed =
+        (format == Format::Avif).then(|| options.avif_speed.max(config.min_avif_speed));
+
+    if let Some(speed) = applied_avif_speed {
+        options.avif_speed = speed;
+    }
+
+    let response_type = match with_lqip {
+        true => "application/json",
+        false => content_type,
+    };
+
+    if let Some(interval) = options.jpeg_restart_interval {
+        if interval == 0 {
+            return Ok(HttpResponse::BadRequest().body("jpeg_restart_interval must be positive"));
+        }
+
+        if format != Format::Jpeg {
+            return Ok(HttpResponse::BadRequest().body("jpeg_restart_interval needs JPEG output"));
+        }
+
+        if options.jpeg_smoothing > 0 {
+            return Ok(HttpResponse::BadRequest()
+                .body("jpeg_restart_interval can't be combined with jpeg_smoothing"));
+        }
+    }
+
+    let output_color_type = match output_color_type
+        .as_deref()
+        .map(|c| ColorType::from_name(c))
+    {
+        None => None,
+        Some(Some(color_type)) if format.supports_color_type(color_type) => Some(color_type),
+        Some(Some(color_type)) => {
+            return Ok(HttpResponse::BadRequest()
+                .body(format!("{format} cannot be encoded as {color_type:?}")))
+        }
+        Some(None) => return Ok(HttpResponse::BadRequest().body("Unsupported output_color_type")),
+    };
+
+    let auto_levels = match auto_levels.is_some_and(|a| *a) {
+        true => {
+            let clip = auto_levels_clip.map_or(levels::DEFAULT_CLIP, |c| *c);
+
+            if !(0. ..=25.).contains(&clip) {
+                return Ok(
+                    HttpResponse::BadRequest().body("auto_levels_clip must be between 0 and 25")
+                );
+            }
+
+            let Some(mode) = auto_levels_mode
+                .as_deref()
+                .map_or(Some(levels::LevelsMode::Luminance), |m| {
+                    levels::LevelsMode::from_name(m)
+                })
+            else {
+                return Ok(HttpResponse::BadRequest()
+                    .body("auto_levels_mode must be luminance or per_channel"));
+            };
+
+            Some((clip, mode))
+        }
+        false => None,
+    };
+
+    let extract_alpha = match extract.as_deref().map(String::as_str) {
+        None => false,
+        Some("alpha") => true,
+        Some(_) => return Ok(HttpResponse::BadRequest().body("extract must be alpha")),
+    };
+
+    if let Some(name) = assign_profile.as_deref() {
+        let Some(profile) = profiles::NamedProfile::from_name(name) else {
+            return Ok(HttpResponse::BadRequest().body(format!("Unknown profile {name:?}")));
+        };
+
+        if !format.supports_icc_profile() {
+            return Ok(
+                HttpResponse::BadRequest().body(format!("{format} output can't embed a profile"))
+            );
+        }
+
+        options.icc_profile = Some(profile.icc());
+    }
+
+    let (width, height) = (width.map(|w| *w), height.map(|h| *h));
+
+    if [width, height]
+        .into_iter()
+        .flatten()
+        .any(|side| side == 0 || side > config.max_output_dimension)
+    {
+        return Ok(HttpResponse::BadRequest().body(format!(
+            "width and height must be between 1 and {}",
+            config.max_output_dimension
+        )));
+    }
+
+    let Some(fit) = fit
+        .as_deref()
+        .map_or(Some(resize::Fit::Contain), |f| resize::Fit::from_name(f))
+    else {
+        return Ok(HttpResponse::BadRequest().body("fit must be exact, contain or cover"));
+    };
+
+    let background_set = background.is_some();
+    let Some(background) = background
+        .as_deref()
+        .map_or(Some(RGBA8::new(255, 255, 255, 255)), |b| {
+            resize::parse_hex_color(b)
+        })
+    else {
+        return Ok(
+            HttpResponse::BadRequest().body("background must be a #rrggbb or #rrggbbaa color")
+        );
+    };
+
+    let output_bit_depth = output_bit_depth.map(|d| *d);
+
+    if output_bit_depth.is_some_and(|d| d != 8 && d != 16) {
+        return Ok(HttpResponse::BadRequest().body("output_bit_depth must be 8 or 16"));
+    }
+
+    let web_ready = web_ready.is_some_and(|w| *w);
+    let auto_orient = auto_orient.map_or(web_ready, |a| *a);
+    let orient_mode = match orient_mode.as_deref() {
+        Some(name) => match orient::OrientMode::from_name(name) {
+            Some(mode) => mode,
+            None => {
+                return Ok(HttpResponse::BadRequest()
+                    .body("orient_mode must be full, rotate_only or none"))
+            }
+        },
+        None if auto_orient => orient::OrientMode::Full,
+        None => orient::OrientMode::None,
+    };
+    let to_srgb = to_srgb.map_or(web_ready, |s| *s);
+    let max_dimension = max_dimension
+        .map(|m| *m)
+        .or(web_ready.then_some(config.web_ready_max_dimension));
+
+    if max_dimension == Some(0) {
+        return Ok(HttpResponse::BadRequest().body("max_dimension must be positive"));
+    }
+
+    // Re-encoding to the same format would only cost time and possibly quality, so hand
+    // back the original bytes, metadata included.
+    if !has_transforms && !optimize && upload_format(&input).as_ref() == Some(&format) {
+        let out = std::fs::read(input.file.path())?;
+
+        // The bytes go back untouched, so make sure they really are what the client said,
+        // rather than e.g. HTML labelled as an image.
+        if !format.matches_signature(&out) {
+            return Ok(HttpResponse::UnprocessableEntity()
+                .body(format!("File content isn't a valid {format} image")));
+        }
+
+        return output::respond(out, content_type, &config, &req)
+            .map_err(actix_web::error::ErrorInternalServerError);
+    }
+
+    let autocrop_tolerance = autocrop
+        .is_some_and(|a| *a)
+        .then(|| autocrop_tolerance.map_or(DEFAULT_AUTOCROP_TOLERANCE, |t| *t));
+
+    let cache_key = match cache.is_enabled() {
+        true => {
+            let key = cache::Cache::key(
+                input.file.as_file(),
+                &(
+                    format,
+                    &options,
+                    orient_mode,
+                    autocrop_tolerance,
+                    (to_srgb, auto_levels),
+                    scale,
+                    (width, height, fit, background, correct_pixel_aspect),
+                    max_dimension,
+                    (output_color_type, extract_alpha),
+                    output_bit_depth,
+                    page,
+                    (with_lqip, optimize, strict_fidelity),
+                ),
+            )?;
+            input.file.as_file().rewind()?;
+
+            Some(key)
+        }
+        false => None,
+    };
+
+    if let Some(hit) = cache_key.as_ref().and_then(|key| cache.get(key)) {
+        let mut res = converted_response(
+            hit,
+            response_type,
+            (applied_quality, applied_avif_speed),
+            &config,
+            &req,
+        )?;
+        res.headers_mut()
+            .insert(cache::X_CACHE, HeaderValue::from_static("HIT"));
+
+        return Ok(res);
+    }
+
+    let original = match optimize {
+        true => Some(std::fs::read(input.file.path())?),
+        false => None,
+    };
+
+    let mut decoded = match decode_upload_page(input, &config, page) {
+        Ok(decoded) => decoded,
+        Err(res) => return Ok(res),
+    };
+
+    // Before orienting, since a transpose would swap the pixel aspect too.
+    if correct_pixel_aspect {
+        decoded
+            .correct_pixel_aspect(config.max_output_dimension)
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+    }
+
+    if let Some(orientation) = decoded.metadata.orientation() {
+        decoded.orient(orient_mode.apply_to(orientation));
+    }
+
+    if let Some(tolerance) = autocrop_tolerance {
+        decoded.autocrop(tolerance);
+    }
+
+    if to_srgb {
+        decoded.convert_to_srgb();
+    }
+
+    if let Some(scale) = scale {
+        decoded
+            .scale(scale, config.max_output_dimension)
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+    }
+
+    decoded
+        .fit_to(width, height, fit, background, config.max_output_dimension)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    if let Some(max) = max_dimension {
+        decoded
+            .fit_within(max)
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+    }
+
+    if let Some((clip, mode)) = auto_levels {
+        decoded.auto_levels(clip, mode);
+    }
+
+    if extract_alpha && !decoded.extract_alpha() {
+        return Ok(HttpResponse::BadRequest().body("The image has no alpha channel to extract"));
+    }
+
+    let had_alpha = decoded.color_type.has_alpha();
+    let translucent = strict_fidelity && decoded.is_translucent();
+
+    let keeps_alpha = match output_color_type {
+        Some(color_type) => color_type.has_alpha(),
+        None => format.supports_alpha(),
+    };
+
+    if !keeps_alpha {
+        decoded.flatten_onto(background);
+    }
+
+    if let Some(color_type) = output_color_type {
+        decoded.convert_color_type(color_type);
+    }
+
+    decoded.adapt_layout_for(&format);
+
+    let has_alpha = decoded.color_type.has_alpha();
+
+    if strict_fidelity {
+        let mut losses = Vec::new();
+
+        if translucent && !has_alpha && !background_set && output_color_type.is_none() {
+            losses.push(format!(
+                "{format} can't store transparency, so it would be flattened onto white \
+                 (set background to pick the color and accept this)"
+            ));
+        }
+
+        if decoded.bit_depth == 16 && !format.supports_16bit() && output_bit_depth != Some(8) {
+            losses.push(format!(
+                "{format} can't store 16-bit samples, so they would be scaled down to 8 bits \
+                 (set output_bit_depth to 8 to accept this)"
+            ));
+        }
+
+        if format.is_lossy() && !explicit_quality {
+            losses.push(format!(
+                "{format} encoding is lossy (set quality to accept this)"
+            ));
+        }
+
+        if !losses.is_empty() {
+            return Ok(HttpResponse::UnprocessableEntity().body(format!(
+                "Conversion would lose information: {}",
+                losses.join("; ")
+            )));
+        }
+    }
+
+    if output_bit_depth == Some(8) {
+        decoded.reduce_to_8bit();
+    }
+
+    let lqip = with_lqip
+        .then(|| decoded.lqip())
+        .transpose()
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    let out = format
+        .encode(&decoded, &options)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    // The upload is only a stand-in for the output when nothing but a smaller file was
+    // asked for.
+    let (out, bytes_saved) = match original {
+        Some(original) if !has_transforms && original.len() <= out.len() => (original, Some(0)),
+        Some(original) => {
+            let saved = original.len().saturating_sub(out.len());
+
+            (out, Some(saved))
+        }
+        None => (out, None),
+    };
+
+    let converted = cache::Cached {
+        body: match lqip {
+            Some(lqip) => serde_json::to_vec(&lqip::WithLqip::new(content_type, &out, lqip))?,
+            None => out,
+        },
+        has_alpha,
+        alpha_flattened: had_alpha && !has_alpha,
+        bytes_saved,
+        raw_layout: (format == Format::Raw).then_some(output::RawLayout {
+            width: decoded.width,
+            height: decoded.height,
+            color_type: decoded.color_type,
+            bit_depth: decoded.bit_depth,
+        }),
+    };
+
+    if let Some(key) = cache_key {
+        cache.insert(key, converted.clone());
+    }
+
+    let mut res = converted_response(
+        converted,
+        response_type,
+        (applied_quality, applied_avif_speed),
+        &config,
+        &req,
+    )?;
+
+    if cache.is_enabled() {
+        res.headers_mut()
+            .insert(cache::X_CACHE, HeaderValue::from_static("MISS"));
+    }
+
+    Ok(res)
+}
+
+/// Options accepted by `/convert_raw`, which takes them from the query string since the
+/// body is the image itself. Each means the same as its `/convert_image` field.
+#[derive(Debug, Deserialize)]
+struct RawQuery {
+    output_type: Option<String>,
+    quality: Option<f32>,
+    auto_orient: Option<bool>,
+    to_srgb: Option<bool>,
+    max_dimension: Option<u32>,
+    web_ready: Option<bool>,
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Option<String>,
+    background: Option<String>,
+}
+
+/// Converts an image sent as the raw request body, typed by its `Content-Type`, for
+/// clients where multipart is a chore:
+///
+/// ```sh
+/// curl --data-binary @in.png -H 'Content-Type: image/png' '.../convert_raw?output_type=webp'
+/// ```
+///
+/// The output type can also be given in an `X-Output-Type` header.
+async fn convert_raw(
+    req: HttpRequest,
+    config: web::Data<config::Config>,
+    cache: web::Data<cache::Cache>,
+    query: web::Query<RawQuery>,
+    payload: web::Payload,
+) -> actix_web::Result<impl Responder, actix_web::Error> {
+    let file = upload::raw_body(&req, payload, config.max_upload_size, &config.temp_dir).await?;
+
+    let RawQuery {
+        output_type,
+        quality,
+        auto_orient,
+        to_srgb,
+        max_dimension,
+        web_ready,
+        width,
+        height,
+        fit,
+        background,
+    } = query.into_inner();
+
+    let output_type = output_type.or_else(|| {
+        req.headers()
+            .get(X_OUTPUT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+    });
+
+    convert_image(
+        req,
+        config,
+        cache,
+        MultipartForm(UploadForm {
+            file,
+            output_type: output_type.map(Json),
+            autocrop: None,
+            autocrop_tolerance: None,
+            jpeg_smoothing: None,
+            jpeg_restart_interval: None,
+            quality: quality.map(Json),
+            auto_orient: auto_orient.map(Json),
+            orient_mode: None,
+            to_srgb: to_srgb.map(Json),
+            auto_levels: None,
+            auto_levels_clip: None,
+            auto_levels_mode: None,
+            max_dimension: max_dimension.map(Json),
+            web_ready: web_ready.map(Json),
+            avif_speed: None,
+            avif_fallback: None,
+            scale: None,
+            deterministic: None,
+            output_bit_depth: None,
+            assign_profile: None,
+            width: width.map(Json),
+            height: height.map(Json),
+            fit: fit.map(Json),
+            background: background.map(Json),
+            correct_pixel_aspect: None,
+            page: None,
+            with_lqip: None,
+            output_color_type: None,
+            strict_fidelity: None,
+            extract: None,
+        }),
+    )
+    .await
+}
+
+/// Sends a conversion's output along with the headers describing it.
+fn converted_response(
+    converted: cache::Cached,
+    content_type: &str,
+    (applied_quality, applied_avif_speed): (Option<f32>, Option<u8>),
+    config: &config::Config,
+    req: &HttpRequest,
+) -> actix_web::Result<HttpResponse> {
+    let mut res = output::respond(converted.body, content_type, config, req)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    if let Some(quality) = applied_quality {
+        res.headers_mut().insert(
+            output::X_APPLIED_QUALITY,
+            HeaderValue::from_str(&quality.to_string()).expect("a number is a valid header value"),
+        );
+    }
+
+    if let Some(speed) = applied_avif_speed {
+        res.headers_mut().insert(
+            output::X_APPLIED_AVIF_SPEED,
+            HeaderValue::from(speed as u16),
+        );
+    }
+
+    res.headers_mut().insert(
+        output::X_OUTPUT_HAS_ALPHA,
+        HeaderValue::from_static(if converted.has_alpha { "true" } else { "false" }),
+    );
+
+    if converted.alpha_flattened {
+        res.headers_mut()
+            .insert(output::X_ALPHA_FLATTENED, HeaderValue::from_static("true"));
+    }
+
+    if let Some(saved) = converted.bytes_saved {
+        res.headers_mut().insert(
+            output::X_BYTES_SAVED,
+            HeaderValue::from_str(&saved.to_string()).expect("a number is a valid header value"),
+        );
+    }
+
+    if let Some(layout) = converted.raw_layout {
+        layout.insert_headers(res.headers_mut());
+    }
+
+    Ok(res)
+}
+
+/// Declares every route once, generating both the registration function and the list of
+/// methods in use, so CORS always allows exactly what's served.
+macro_rules! routes {
+    ($($method:ident $path:literal => $handler:path),* $(,)?) => {
+        const ROUTE_METHODS: &[Method] = &[$(Method::$method),*];
+
+        fn configure_routes(cfg: &mut web::ServiceConfig) {
+            $(cfg.route($path, web::method(Method::$method).to($handler));)*
+        }
+    };
+}
+
+routes! {
+    POST "/convert_image" => convert_image,
+    POST "/convert_raw" => convert_raw,
+    POST "/colors" => colors::dominant_colors,
+    POST "/histogram" => histogram::histogram,
+    POST "/info" => info::info,
+    POST "/srcset" => srcset::srcset,
+    POST "/frames" => frames::frames,
+    GET "/readyz" => health::readyz,
+    GET "/formats" => formats,
+    GET "/cache" => cache::stats,
+    GET "/bench" => bench::bench,
+    POST "/jobs" => jobs::submit,
+    GET "/jobs/{id}" => jobs::status,
+    GET "/jobs/{id}/result" => jobs::result,
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format(|buf, record| {
+            let request_id = request_id::current().map_or_else(String::new, |id| format!(" {id}"));
+
+            writeln!(
+                buf,
+                "[{} {} {}{request_id}] {}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                record.args()
+            )
+        })
+        .init();
+
+    let config = config::Config::from_env().map_err(std::io::Error::other)?;
+
+    let data = web::Data::new(config.clone());
+    let cpu_budget = web::Data::new(budget::CpuBudget::new(
+        config.cpu_budget,
+        config.cpu_budget_window,
+        config.trusted_proxies.clone(),
+    ));
+    let cache = web::Data::new(cache::Cache::new(config.cache_size, config.cache_ttl));
+    let jobs = web::Data::new(jobs::Jobs::new(
+        config.job_concurrency,
+        config.job_ttl,
+        config.max_jobs,
+        config.max_job_storage,
+    ));
+    let readiness = web::Data::new(match config.self_test {
+        true => health::Readiness::self_test(),
+        false => health::Readiness::skipped(),
+    });
+
+    let server = HttpServer::new(move || {
+        let cors = Cors::default()
+            .allow_any_origin()
+            .allowed_methods(ROUTE_METHODS.iter().cloned())
+            .allowed_headers([
+                actix_web::http::header::CONTENT_TYPE,
+                actix_web::http::header::AUTHORIZATION,
+                X_OUTPUT_TYPE,
+            ])
+            .expose_headers([
+                request_id::X_REQUEST_ID,
+                output::X_APPLIED_QUALITY,
+                output::X_APPLIED_AVIF_SPEED,
+                output::X_OUTPUT_HAS_ALPHA,
+                output::X_ALPHA_FLATTENED,
+                output::X_BYTES_SAVED,
+                output::X_IMAGE_WIDTH,
+                output::X_IMAGE_HEIGHT,
+                output::X_IMAGE_COLOR_TYPE,
+                output::X_IMAGE_BIT_DEPTH,
+                cache::X_CACHE,
+            ])
+            .max_age(3600);
+
+        App::new()
+            .app_data(data.clone())
+            .app_data(readiness.clone())
+            .app_data(cache.clone())
+            .app_data(jobs.clone())
+            .app_data(cpu_budget.clone())
+            .app_data(upload::multipart_config(
+                data.max_upload_size,
+                data.multipart_memory_limit,
+            ))
+            .app_data(TempFileConfig::default().directory(&data.temp_dir))
+            .wrap_fn({
+                let max_parts = data.max_multipart_parts;
+
+                move |mut req, srv| {
+                    upload::limit_parts(&mut req, max_parts);
+                    srv.call(req)
+                }
+            })
+            .wrap_fn({
+                let cpu_budget = cpu_budget.clone();
+
+                move |req, srv| budget::guard(&cpu_budget, req, srv)
+            })
+            .wrap_fn({
+                let secret = data.signing_secret.clone();
+
+                move |req, srv| signing::guard(secret.as_deref(), req, srv)
+            })
+            // Browsers mustn't second-guess our content types, e.g. rendering an image
+            // that happens to look like HTML.
+            .wrap(DefaultHeaders::new().add((X_CONTENT_TYPE_OPTIONS, "nosniff")))
+            .wrap(Compress::default())
+            .wrap(cors)
+            .wrap_fn(|req, srv| {
+                let id = request_id::from_request(&req);
+
+                request_id::scope(id, srv.call(req))
+            })
+            .wrap(Logger::new(
+                r#"%a "%r" %s %b %Dms request_id=%{x-request-id}o"#,
+            ))
+            .configure(configure_routes)
+    })
+    // actix treats SIGINT as a forced shutdown, so take over signal handling and drain
+    // in-flight conversions for both SIGINT and SIGTERM.
+    .disable_signals()
+    .workers(config.http_workers)
+    .keep_alive(match config.keep_alive.is_zero() {
+        true => KeepAlive::Disabled,
+        false => KeepAlive::Timeout(config.keep_alive),
+    })
+    .shutdown_timeout(config.shutdown_timeout.as_secs())
+    .bind("127.0.0.1:8080")?
+    .run();
+
+    let handle = server.handle();
+
+    actix_web::rt::spawn({
+        let handle = handle.clone();
+
+        async move {
+            if actix_web::rt::signal::ctrl_c().await.is_ok() {
+                handle.stop(true).await;
+            }
+        }
+    });
+
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+
+        actix_web::rt::spawn(async move {
+            terminate.recv().await;
+            handle.stop(true).await;
+        });
+    }
+
+    server.await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::http::StatusCode;
+
+    use super::*;
+
+    /// Solid red in CMYK.
+    const RED_CMYK: [u8; 4] = [0, 255, 255, 0];
+
+    fn decode(mut format: Format, buf: &[u8]) -> Decoded {
+        format.decode(Cursor::new(buf), u64::MAX).unwrap()
+    }
+
+    /// A solid 8x8 CMYK JPEG written the way Adobe's software does: inverted, as YCCK.
+    fn adobe_ycck() -> Vec<u8> {
+        let mut encoder = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_CMYK);
+        encoder.set_color_space(mozjpeg::ColorSpace::JCS_YCCK);
+        encoder.set_quality(100.);
+        encoder.set_size(8, 8);
+
+        let mut comp = encoder.start_compress(Vec::new()).unwrap();
+        comp.write_scanlines(&RED_CMYK.map(|s| 255 - s).repeat(64))
+            .unwrap();
+
+        comp.finish().unwrap()
+    }
+
+    fn assert_close(actual: &[u8], expected: &[u8]) {
+        assert!(
+            actual
+                .iter()
+                .zip(expected)
+                .all(|(a, e)| a.abs_diff(*e) <= 4),
+            "{actual:?} isn't close to {expected:?}"
+        );
+    }
+
+    #[test]
+    fn adobe_ycck_jpegs_are_uninverted() {
+        let decoded = decode(Format::Jpeg, &adobe_ycck());
+
+        assert_eq!(decoded.color_type, ColorType::Cmyk);
+        assert_close(&decoded.bytes[..4], &RED_CMYK);
+
+        let RGBA8 {
+            r, g, b, ..
+        } = decoded.rgba_pixels()[0];
+        assert_close(&[r, g, b], &[255, 0, 0]);
+    }
+
+    #[test]
+    fn adobe_ycck_jpegs_convert_to_rgb_formats() {
+        for mut format in [Format::Png, Format::WebP, Format::Pnm] {
+            let mut decoded = decode(Format::Jpeg, &adobe_ycck());
+            decoded.adapt_layout_for(&format);
+
+            let encoded = format
+                .encode(&decoded, &EncodeOptions::quick(100.))
+                .unwrap();
+            let rgb = decode(format, &encoded);
+
+            assert_eq!(rgb.color_type, ColorType::Rgb, "{format}");
+            assert_eq!((rgb.width, rgb.height), (8, 8));
+            for px in rgb.bytes.chunks_exact(3) {
+                assert_close(px, &[255, 0, 0]);
+            }
+        }
+    }
+
+    #[test]
+    fn cmyk_is_kept_where_it_can_be() {
+        for format in [Format::Jpeg, Format::Tiff] {
+            let mut decoded = decode(Format::Jpeg, &adobe_ycck());
+            decoded.adapt_layout_for(&format);
+
+            assert_eq!(decoded.color_type, ColorType::Cmyk, "{format}");
+        }
+    }
+
+    #[test]
+    fn cmyk_jpegs_round_trip() {
+        let image = Decoded {
+            bytes: RED_CMYK.repeat(64),
+            color_type: ColorType::Cmyk,
+            width: 8,
+            height: 8,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        };
+        let out = Format::Jpeg
+            .encode(&image, &EncodeOptions::quick(100.))
+            .unwrap();
+
+        assert_close(&decode(Format::Jpeg, &out).bytes[..4], &RED_CMYK);
+    }
+
+    #[test]
+    fn restart_markers_follow_the_interval() {
+        let image = Decoded {
+            bytes: (0..64 * 64).map(|i| i as u8).collect(),
+            color_type: ColorType::Grayscale,
+            width: 64,
+            height: 64,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        };
+        let options = EncodeOptions {
+            jpeg_restart_interval: Some(4),
+            ..EncodeOptions::quick(90.)
+        };
+
+        let jpeg = Format::Jpeg.encode(&image, &options).unwrap();
+
+        let dri = jpeg
+            .windows(6)
+            .find(|w| w[..2] == [0xFF, 0xDD])
+            .expect("no DRI marker");
+        assert_eq!(dri[2..], [0, 4, 0, 4]);
+
+        // 64 grayscale 8x8 MCUs, with a marker between every 4, cycling through RST0-7.
+        let restarts: Vec<_> = jpeg
+            .windows(2)
+            .filter(|w| w[0] == 0xFF && (0xD0..=0xD7).contains(&w[1]))
+            .map(|w| w[1])
+            .collect();
+        assert_eq!(restarts, (0..15).map(|i| 0xD0 + i % 8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn jpeg_smoothing_keeps_dimensions() {
+        // Deterministic noise, which smoothing should visibly change.
+        let image = Decoded {
+            bytes: (0u32..48 * 32 * 3)
+                .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+                .collect(),
+            color_type: ColorType::Rgb,
+            width: 48,
+            height: 32,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        };
+
+        let [plain, smoothed] = [0, 100].map(|jpeg_smoothing| {
+            let options = EncodeOptions {
+                jpeg_smoothing,
+                ..EncodeOptions::quick(90.)
+            };
+
+            Format::Jpeg.encode(&image, &options).unwrap()
+        });
+
+        assert_ne!(plain, smoothed);
+
+        let decoded = decode(Format::Jpeg, &smoothed);
+        assert_eq!((decoded.width, decoded.height), (48, 32));
+    }
+
+    #[test]
+    fn indexed_pngs_expand_through_trns() {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, 2, 1);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_palette(vec![255, 0, 0, 0, 0, 255]);
+        // Only the first entry is transparent; the rest default to opaque.
+        encoder.set_trns(vec![0]);
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(&[0, 1])
+            .unwrap();
+
+        let decoded = decode(Format::Png, &png);
+
+        assert_eq!(decoded.color_type, ColorType::Rgba);
+        assert_eq!(decoded.bit_depth, 8);
+        assert_eq!(decoded.bytes, [255, 0, 0, 0, 0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn deterministic_encodes_are_byte_identical() {
+        let image = Decoded {
+            bytes: (0u32..64 * 64 * 3).map(|i| (i * 7 % 251) as u8).collect(),
+            color_type: ColorType::Rgb,
+            width: 64,
+            height: 64,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        };
+        let options = EncodeOptions::quick(90.);
+        assert!(options.deterministic);
+
+        for mut format in [
+            Format::Avif,
+            Format::Png,
+            Format::Jpeg,
+            Format::WebP,
+            Format::Pnm,
+            Format::Tiff,
+        ] {
+            let first = format.encode(&image, &options).unwrap();
+            let second = format.encode(&image, &options).unwrap();
+
+            assert!(first == second, "{format} output differs between runs");
+        }
+    }
+
+    /// A 4x2 16-bit grayscale image whose samples would all be corrupted by truncation or
+    /// byte swapping.
+    const GRAY16: [u16; 8] = [0, 1, 255, 256, 0x1234, 0xABCD, 65534, 65535];
+
+    fn gray16() -> Decoded {
+        Decoded {
+            bytes: GRAY16.iter().flat_map(|s| s.to_ne_bytes()).collect(),
+            color_type: ColorType::Grayscale,
+            width: 4,
+            height: 2,
+            bit_depth: 16,
+            metadata: Metadata::default(),
+        }
+    }
+
+    fn round_trip(mut format: Format, image: &Decoded) -> Decoded {
+        let encoded = format.encode(image, &EncodeOptions::quick(100.)).unwrap();
+
+        decode(format, &encoded)
+    }
+
+    #[test]
+    fn gray16_survives_tiff_to_png() {
+        let tiff = round_trip(Format::Tiff, &gray16());
+        let png = round_trip(Format::Png, &tiff);
+
+        for decoded in [tiff, png] {
+            assert_eq!(decoded.color_type, ColorType::Grayscale);
+            assert_eq!(decoded.bit_depth, 16);
+            assert_eq!(decoded.bytes, gray16().bytes);
+        }
+    }
+
+    #[test]
+    fn gray16_survives_png_to_tiff() {
+        let png = round_trip(Format::Png, &gray16());
+        let tiff = round_trip(Format::Tiff, &png);
+
+        for decoded in [png, tiff] {
+            assert_eq!(decoded.color_type, ColorType::Grayscale);
+            assert_eq!(decoded.bit_depth, 16);
+            assert_eq!(decoded.bytes, gray16().bytes);
+        }
+    }
+
+    /// `GRAY16` as a PNG written straight from big-endian samples, bypassing our encoder.
+    fn reference_gray16_png() -> Vec<u8> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, 4, 2);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Sixteen);
+
+        let samples: Vec<u8> = GRAY16.iter().flat_map(|s| s.to_be_bytes()).collect();
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(&samples)
+            .unwrap();
+
+        png
+    }
+
+    #[test]
+    fn sixteen_bit_pngs_match_a_reference() {
+        assert_eq!(
+            decode(Format::Png, &reference_gray16_png()).bytes,
+            gray16().bytes
+        );
+
+        let ours = Format::Png
+            .encode(&gray16(), &EncodeOptions::quick(100.))
+            .unwrap();
+        let mut reader = png::Decoder::new(Cursor::new(ours)).read_info().unwrap();
+        let mut samples = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut samples).unwrap();
+
+        assert_eq!(
+            samples,
+            GRAY16
+                .iter()
+                .flat_map(|s| s.to_be_bytes())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn failed_conversions_leave_no_temp_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config::Config {
+            temp_dir: dir.path().to_owned(),
+            ..config::Config::from_env().unwrap()
+        };
+
+        let upload = |content_type: mime::Mime| {
+            let mut file = tempfile::NamedTempFile::new_in(&config.temp_dir).unwrap();
+            file.write_all(b"\x89PNG\r\n\x1a\n but then garbage")
+                .unwrap();
+
+            TempFile {
+                file,
+                content_type: Some(content_type),
+                file_name: None,
+                size: 0,
+            }
+        };
+
+        // Undecodable, and refused before decoding.
+        for content_type in [mime::IMAGE_PNG, mime::TEXT_PLAIN] {
+            assert!(decode_upload(upload(content_type), &config).is_err());
+            assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+        }
+    }
+
+    /// An 8x8 JPEG whose frame header claims `width`x`height`, cut off right after the scan
+    /// header, so decoding any scanline would fail.
+    fn truncated_jpeg(width: u16, height: u16) -> Vec<u8> {
+        let image = Decoded {
+            bytes: vec![128; 8 * 8],
+            color_type: ColorType::Grayscale,
+            width: 8,
+            height: 8,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        };
+        let mut jpeg = Format::Jpeg
+            .encode(&image, &EncodeOptions::quick(90.))
+            .unwrap();
+
+        let marker = |codes: &[u8]| {
+            jpeg.windows(2)
+                .position(|w| w[0] == 0xFF && codes.contains(&w[1]))
+                .unwrap()
+        };
+
+        let (sof, sos) = (marker(&[0xC0, 0xC1, 0xC2]), marker(&[0xDA]));
+        jpeg[sof + 5..sof + 7].copy_from_slice(&height.to_be_bytes());
+        jpeg[sof + 7..sof + 9].copy_from_slice(&width.to_be_bytes());
+
+        let sos_len = u16::from_be_bytes([jpeg[sos + 2], jpeg[sos + 3]]) as usize;
+        jpeg.truncate(sos + 2 + sos_len);
+
+        jpeg
+    }
+
+    #[test]
+    fn jpeg_bombs_are_rejected_from_the_header() {
+        let error = Format::Jpeg
+            .decode(Cursor::new(truncated_jpeg(60_000, 60_000)), 100_000_000)
+            .unwrap_err();
+
+        assert!(
+            matches!(
+                error.downcast_ref(),
+                Some(Error::TooManyPixels(Format::Jpeg, 60_000, 60_000, _))
+            ),
+            "{error:#}"
+        );
+    }
+
+    fn upload_form(image: &[u8], content_type: mime::Mime, output_type: &str) -> UploadForm {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(image).unwrap();
+        file.rewind().unwrap();
+
+        UploadForm {
+            file: TempFile {
+                file,
+                content_type: Some(content_type),
+                file_name: None,
+                size: image.len(),
+            },
+            output_type: Some(Json(output_type.to_string())),
+            autocrop: None,
+            autocrop_tolerance: None,
+            jpeg_smoothing: None,
+            jpeg_restart_interval: None,
+            quality: None,
+            auto_orient: None,
+            orient_mode: None,
+            to_srgb: None,
+            auto_levels: None,
+            auto_levels_clip: None,
+            auto_levels_mode: None,
+            max_dimension: None,
+            web_ready: None,
+            avif_speed: None,
+            avif_fallback: None,
+            scale: None,
+            deterministic: None,
+            output_bit_depth: None,
+            assign_profile: None,
+            width: None,
+            height: None,
+            fit: None,
+            background: None,
+            correct_pixel_aspect: None,
+            page: None,
+            with_lqip: None,
+            output_color_type: None,
+            strict_fidelity: None,
+            extract: None,
+        }
+    }
+
+    /// Runs `form` through `/convert_image`, returning the status and body.
+    async fn convert(form: UploadForm) -> (StatusCode, Vec<u8>) {
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let res = convert_image(
+            req.clone(),
+            web::Data::new(config::Config::from_env().unwrap()),
+            web::Data::new(cache::Cache::new(0, Duration::ZERO)),
+            MultipartForm(form),
+        )
+        .await
+        .unwrap()
+        .respond_to(&req);
+
+        let status = res.status();
+        let Ok(body) = actix_web::body::to_bytes(res.into_body()).await else {
+            panic!("couldn't read the response");
+        };
+
+        (status, body.to_vec())
+    }
+
+    /// A 2x1 PNG: opaque blue, then fully transparent.
+    fn half_transparent_png() -> Vec<u8> {
+        let image = Decoded {
+            bytes: vec![0, 0, 255, 255, 0, 255, 0, 0],
+            color_type: ColorType::Rgba,
+            width: 2,
+            height: 1,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        };
+
+        Format::Png
+            .encode(&image, &EncodeOptions::quick(100.))
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn strict_fidelity_takes_background_as_the_flatten_opt_in() {
+        let mut form = upload_form(&half_transparent_png(), mime::IMAGE_PNG, "pnm");
+        form.strict_fidelity = Some(Json(true));
+
+        let (status, body) = convert(form).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(String::from_utf8_lossy(&body).contains("transparency"));
+
+        let mut form = upload_form(&half_transparent_png(), mime::IMAGE_PNG, "pnm");
+        form.strict_fidelity = Some(Json(true));
+        form.background = Some(Json("#ff0000".to_string()));
+
+        let (status, body) = convert(form).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let flattened = decode(Format::Pnm, &body);
+        assert_eq!(flattened.color_type, ColorType::Rgb);
+        assert_eq!(flattened.bytes, [0, 0, 255, 255, 0, 0]);
+    }
+
+    #[actix_web::test]
+    async fn transparent_gray_flattens_onto_colored_backgrounds() {
+        let image = Decoded {
+            bytes: vec![200, 255, 200, 0],
+            color_type: ColorType::GrayscaleAlpha,
+            width: 2,
+            height: 1,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        };
+        let png = Format::Png
+            .encode(&image, &EncodeOptions::quick(100.))
+            .unwrap();
+
+        let mut form = upload_form(&png, mime::IMAGE_PNG, "pnm");
+        form.background = Some(Json("#00ff00".to_string()));
+
+        let (status, body) = convert(form).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(decode(Format::Pnm, &body).bytes, [200, 200, 200, 0, 255, 0]);
+    }
+
+    #[actix_web::test]
+    async fn strict_fidelity_catches_depth_lost_after_to_srgb() {
+        // 16-bit RGB, tagged with a profile so to_srgb has work to do.
+        let mut image = gray16();
+        image.convert_color_type(ColorType::Rgb);
+        let options = EncodeOptions {
+            icc_profile: Some(lcms2::Profile::new_srgb().icc().unwrap()),
+            ..EncodeOptions::quick(100.)
+        };
+        let png = Format::Png.encode(&image, &options).unwrap();
+
+        let strict = |output_bit_depth: Option<u8>| {
+            let mut form = upload_form(&png, mime::IMAGE_PNG, "jpeg");
+            form.to_srgb = Some(Json(true));
+            form.strict_fidelity = Some(Json(true));
+            form.quality = Some(Json(90.));
+            form.output_bit_depth = output_bit_depth.map(Json);
+            form
+        };
+
+        let (status, body) = convert(strict(None)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(String::from_utf8_lossy(&body).contains("16-bit"));
+
+        let (status, _) = convert(strict(Some(8))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Lossless 16-bit outputs keep every sample through the sRGB conversion.
+        let mut form = strict(None);
+        form.output_type = Some(Json("png".to_string()));
+        let (status, body) = convert(form).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(decode(Format::Png, &body).bit_depth, 16);
+    }
 }
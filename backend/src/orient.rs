@@ -0,0 +1,377 @@
+use crate::Decoded;
+
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// How much of an EXIF orientation to apply. Each orientation is a rotation, possibly
+/// preceded by a horizontal mirror, so `RotateOnly` keeps just the rotation:
+///
+/// | EXIF | `Full`                   | `RotateOnly`   |
+/// |------|--------------------------|----------------|
+/// | 1    | nothing                  | nothing        |
+/// | 2    | mirror                   | nothing        |
+/// | 3    | rotate 180°              | rotate 180°    |
+/// | 4    | mirror, rotate 180°      | rotate 180°    |
+/// | 5    | mirror, rotate 90° CCW   | rotate 90° CCW |
+/// | 6    | rotate 90° CW            | rotate 90° CW  |
+/// | 7    | mirror, rotate 90° CW    | rotate 90° CW  |
+/// | 8    | rotate 90° CCW           | rotate 90° CCW |
+///
+/// `None` leaves the pixels as stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OrientMode {
+    Full,
+    RotateOnly,
+    None,
+}
+
+impl OrientMode {
+    pub fn from_name(name: &str) -> Option<OrientMode> {
+        match name {
+            "full" => Some(OrientMode::Full),
+            "rotate_only" => Some(OrientMode::RotateOnly),
+            "none" => Some(OrientMode::None),
+
+            _ => None,
+        }
+    }
+
+    /// The orientation to actually apply under this mode.
+    pub fn apply_to(&self, orientation: u16) -> u16 {
+        match (self, orientation) {
+            (OrientMode::None, _) => 1,
+            (OrientMode::RotateOnly, 2) => 1,
+            (OrientMode::RotateOnly, 4) => 3,
+            (OrientMode::RotateOnly, 5) => 8,
+            (OrientMode::RotateOnly, 7) => 6,
+            (_, orientation) => orientation,
+        }
+    }
+}
+
+/// Reads the orientation tag (1-8) from IFD0 of a raw EXIF payload.
+pub fn exif_orientation(exif: &[u8]) -> Option<u16> {
+    let big_endian = match exif.get(..4)? {
+        b"MM\0*" => true,
+        b"II*\0" => false,
+        _ => return None,
+    };
+
+    let u16_at = |offset: usize| {
+        let bytes = [*exif.get(offset)?, *exif.get(offset + 1)?];
+
+        Some(match big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    };
+
+    let u32_at = |offset: usize| {
+        let bytes: [u8; 4] = exif.get(offset..offset + 4)?.try_into().ok()?;
+
+        Some(match big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+
+    (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(ORIENTATION_TAG))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+impl Decoded {
+    /// Applies an EXIF orientation (1-8) to the pixels, so the image displays upright
+    /// without the tag.
+    pub fn orient(&mut self, orientation: u16) {
+        match orientation {
+            2 => self.flip_horizontal(),
+            3 => {
+                self.flip_horizontal();
+                self.flip_vertical();
+            }
+            4 => self.flip_vertical(),
+            5 => self.transpose(),
+            // Rotate 90° clockwise.
+            6 => {
+                self.transpose();
+                self.flip_horizontal();
+            }
+            7 => {
+                self.transpose();
+                self.flip_horizontal();
+                self.flip_vertical();
+            }
+            // Rotate 90° counter-clockwise.
+            8 => {
+                self.transpose();
+                self.flip_vertical();
+            }
+            _ => {}
+        }
+    }
+
+    pub fn flip_horizontal(&mut self) {
+        let bpp = self.bytes_per_pixel();
+        let width = self.width as usize;
+
+        for row in self.bytes.chunks_exact_mut(width * bpp) {
+            for x in 0..width / 2 {
+                let mirrored = width - 1 - x;
+
+                for c in 0..bpp {
+                    row.swap(x * bpp + c, mirrored * bpp + c);
+                }
+            }
+        }
+    }
+
+    pub fn flip_vertical(&mut self) {
+        let stride = self.width as usize * self.bytes_per_pixel();
+        let height = self.height as usize;
+
+        for y in 0..height / 2 {
+            let (top, bottom) = self.bytes.split_at_mut((height - 1 - y) * stride);
+
+            top[y * stride..(y + 1) * stride].swap_with_slice(&mut bottom[..stride]);
+        }
+    }
+
+    /// Mirrors the image across its main diagonal, swapping width and height.
+    pub fn transpose(&mut self) {
+        let bpp = self.bytes_per_pixel();
+        let width = self.width as usize;
+        let height = self.height as usize;
+
+        let mut out = vec![0; self.bytes.len()];
+
+        for y in 0..height {
+            for x in 0..width {
+                let src = (y * width + x) * bpp;
+                let dst = (x * height + y) * bpp;
+
+                out[dst..dst + bpp].copy_from_slice(&self.bytes[src..src + bpp]);
+            }
+        }
+
+        self.bytes = out;
+        (self.width, self.height) = (self.height, self.width);
+    }
+}
+
+/// Maps the primary item's irot/imir transform properties in an AVIF (HEIF) container
+/// to the equivalent EXIF orientation. HEIF applies rotation before mirroring.
+pub fn avif_orientation(avif: &[u8]) -> Option<u16> {
+    let meta = find_box(avif, b"meta")?.get(4..)?;
+    let primary = read_pitm(find_box(meta, b"pitm")?)?;
+
+    let iprp = find_box(meta, b"iprp")?;
+    let properties: Vec<(&[u8; 4], &[u8])> = boxes(find_box(iprp, b"ipco")?).collect();
+
+    let mut rotation = 0;
+    let mut mirror = None;
+
+    for index in read_ipma(find_box(iprp, b"ipma")?, primary)? {
+        match properties.get(index.checked_sub(1)?)? {
+            (b"irot", data) => rotation = data.first()? & 0b11,
+            (b"imir", data) => mirror = Some(data.first()? & 1),
+            _ => {}
+        }
+    }
+
+    // irot is in 90° counter-clockwise steps; imir's axis 0 is vertical (a left-right
+    // flip) and 1 is horizontal (a top-bottom flip).
+    let orientation = match (rotation, mirror) {
+        (0, None) => 1,
+        (1, None) => 8,
+        (2, None) => 3,
+        (_, None) => 6,
+        (0, Some(0)) | (2, Some(1)) => 2,
+        (2, Some(0)) | (0, Some(1)) => 4,
+        (3, Some(0)) | (1, Some(1)) => 5,
+        _ => 7,
+    };
+
+    (orientation != 1).then_some(orientation)
+}
+
+/// Reads the primary item's pasp property in an AVIF (HEIF) container as the width of
+/// each pixel relative to its height, if the pixels aren't square.
+pub fn avif_pixel_aspect(avif: &[u8]) -> Option<f64> {
+    let meta = find_box(avif, b"meta")?.get(4..)?;
+    let primary = read_pitm(find_box(meta, b"pitm")?)?;
+
+    let iprp = find_box(meta, b"iprp")?;
+    let properties: Vec<(&[u8; 4], &[u8])> = boxes(find_box(iprp, b"ipco")?).collect();
+
+    read_ipma(find_box(iprp, b"ipma")?, primary)?
+        .into_iter()
+        .find_map(|index| match properties.get(index.checked_sub(1)?)? {
+            (b"pasp", data) => {
+                let h_spacing = u32::from_be_bytes(data.get(..4)?.try_into().ok()?);
+                let v_spacing = u32::from_be_bytes(data.get(4..8)?.try_into().ok()?);
+
+                crate::pixel_aspect(v_spacing as f64, h_spacing as f64)
+            }
+            _ => None,
+        })
+}
+
+/// Iterates over the ISOBMFF boxes in `data`, yielding each one's type and payload.
+pub fn boxes(mut data: &[u8]) -> impl Iterator<Item = (&[u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let size = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+        let kind: &[u8; 4] = data.get(4..8)?.try_into().ok()?;
+
+        let (header, size) = match size {
+            0 => (8, data.len()),
+            1 => (
+                16,
+                u64::from_be_bytes(data.get(8..16)?.try_into().ok()?) as usize,
+            ),
+            size => (8, size),
+        };
+
+        let payload = data.get(header..size)?;
+        data = &data[size..];
+
+        Some((kind, payload))
+    })
+}
+
+pub fn find_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(data)
+        .find(|(k, _)| *k == kind)
+        .map(|(_, payload)| payload)
+}
+
+/// Reads the primary item ID from a `pitm` full box.
+fn read_pitm(pitm: &[u8]) -> Option<u32> {
+    match pitm.first()? {
+        0 => Some(u16::from_be_bytes(pitm.get(4..6)?.try_into().ok()?) as u32),
+        _ => Some(u32::from_be_bytes(pitm.get(4..8)?.try_into().ok()?)),
+    }
+}
+
+/// Returns the 1-based `ipco` indices of the properties associated with `item`.
+fn read_ipma(ipma: &[u8], item: u32) -> Option<Vec<usize>> {
+    let version = *ipma.first()?;
+    let wide_index = ipma.get(3)? & 1 == 1;
+    let entries = u32::from_be_bytes(ipma.get(4..8)?.try_into().ok()?);
+
+    let mut pos = 8;
+
+    for _ in 0..entries {
+        let id = match version {
+            0 => {
+                pos += 2;
+                u16::from_be_bytes(ipma.get(pos - 2..pos)?.try_into().ok()?) as u32
+            }
+            _ => {
+                pos += 4;
+                u32::from_be_bytes(ipma.get(pos - 4..pos)?.try_into().ok()?)
+            }
+        };
+
+        let count = *ipma.get(pos)? as usize;
+        pos += 1;
+
+        // The top bit of each association marks it essential; the rest is the index.
+        let indices = (0..count)
+            .map(|i| match wide_index {
+                true => {
+                    let at = pos + i * 2;
+                    Some(
+                        (u16::from_be_bytes(ipma.get(at..at + 2)?.try_into().ok()?) & 0x7fff)
+                            as usize,
+                    )
+                }
+                false => Some((ipma.get(pos + i)? & 0x7f) as usize),
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        if id == item {
+            return Some(indices);
+        }
+
+        pos += count * if wide_index { 2 } else { 1 };
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bx(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        [&(8 + payload.len() as u32).to_be_bytes(), kind, payload].concat()
+    }
+
+    /// A minimal AVIF header whose primary item 1 has `properties`. Item 2 is associated
+    /// with an extra irot 1, which must not leak into the primary's orientation.
+    fn avif(properties: &[(&[u8; 4], u8)]) -> Vec<u8> {
+        let mut ipco: Vec<u8> = properties
+            .iter()
+            .flat_map(|&(kind, value)| bx(kind, &[value]))
+            .collect();
+        ipco.extend(bx(b"irot", &[1]));
+
+        let decoy = properties.len() as u8 + 1;
+        let ipma = [
+            &[0, 0, 0, 0, 0, 0, 0, 2][..],
+            &[0, 1, properties.len() as u8],
+            &(1..decoy).map(|i| 0x80 | i).collect::<Vec<_>>(),
+            &[0, 2, 1, decoy],
+        ]
+        .concat();
+
+        let meta = [
+            &[0, 0, 0, 0][..],
+            &bx(b"pitm", &[0, 0, 0, 0, 0, 1]),
+            &bx(b"iprp", &[bx(b"ipco", &ipco), bx(b"ipma", &ipma)].concat()),
+        ]
+        .concat();
+
+        [bx(b"ftyp", b"avifmif1"), bx(b"meta", &meta)].concat()
+    }
+
+    #[test]
+    fn avif_transforms_map_to_exif_orientations() {
+        for (properties, orientation) in [
+            (&[][..], None),
+            (&[(b"irot", 1)], Some(8)),
+            (&[(b"irot", 2)], Some(3)),
+            (&[(b"irot", 3)], Some(6)),
+            (&[(b"imir", 0)], Some(2)),
+            (&[(b"imir", 1)], Some(4)),
+            (&[(b"irot", 1), (b"imir", 0)], Some(7)),
+            (&[(b"irot", 3), (b"imir", 0)], Some(5)),
+        ] {
+            assert_eq!(
+                avif_orientation(&avif(properties)),
+                orientation,
+                "{properties:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn ipma_reads_wide_ids_and_indices() {
+        // Version 1 (32-bit item IDs) with flag 1 (15-bit indices).
+        let ipma = [
+            &[1, 0, 0, 1, 0, 0, 0, 2][..],
+            &[0, 0, 0, 7, 1, 0x80, 0x03],
+            &[0, 0, 0, 9, 2, 0x01, 0x00, 0x00, 0x02],
+        ]
+        .concat();
+
+        assert_eq!(read_ipma(&ipma, 7), Some(vec![3]));
+        assert_eq!(read_ipma(&ipma, 9), Some(vec![256, 2]));
+        assert_eq!(read_ipma(&ipma, 8), None);
+    }
+}
@@ -0,0 +1,146 @@
+use std::io::{self, Write};
+
+use actix_files::NamedFile;
+use actix_web::{
+    http::header::{
+        self, AcceptEncoding, ContentEncoding, Encoding, Header, HeaderName, HeaderValue,
+    },
+    HttpRequest, HttpResponse,
+};
+use flate2::{write::GzEncoder, Compression};
+
+use crate::{config::Config, ColorType};
+
+/// The quality a lossy encode actually used, after any instance ceiling.
+pub const X_APPLIED_QUALITY: HeaderName = HeaderName::from_static("x-applied-quality");
+/// The speed an AVIF encode actually used, after any instance floor.
+pub const X_APPLIED_AVIF_SPEED: HeaderName = HeaderName::from_static("x-applied-avif-speed");
+/// Whether the encoded image kept an alpha channel.
+pub const X_OUTPUT_HAS_ALPHA: HeaderName = HeaderName::from_static("x-output-has-alpha");
+/// Present when the source's alpha was composited onto white to fit the output.
+pub const X_ALPHA_FLATTENED: HeaderName = HeaderName::from_static("x-alpha-flattened");
+/// How many bytes re-encoding to the same format saved, when `output_type` was left out.
+pub const X_BYTES_SAVED: HeaderName = HeaderName::from_static("x-bytes-saved");
+/// Dimensions, channel layout and bits per sample of a `raw` output.
+pub const X_IMAGE_WIDTH: HeaderName = HeaderName::from_static("x-image-width");
+pub const X_IMAGE_HEIGHT: HeaderName = HeaderName::from_static("x-image-height");
+pub const X_IMAGE_COLOR_TYPE: HeaderName = HeaderName::from_static("x-image-color-type");
+pub const X_IMAGE_BIT_DEPTH: HeaderName = HeaderName::from_static("x-image-bit-depth");
+
+/// Uncompressed image formats that actix's `Compress` middleware passes over, since it
+/// skips every `image/*` type. These are gzipped here instead.
+const COMPRESSIBLE_IMAGES: &[&str] = &[
+    "image/bmp",
+    "image/tiff",
+    "image/x-portable-anymap",
+    "image/x-tga",
+];
+
+/// Non-image types that are already compressed, which `Compress` would otherwise
+/// spend CPU on for no gain.
+const INCOMPRESSIBLE: &[&str] = &["application/zip"];
+
+/// How the pixels of a `raw` output are laid out: rows top to bottom, samples interleaved
+/// per pixel, 16-bit samples big-endian.
+#[derive(Debug, Clone, Copy)]
+pub struct RawLayout {
+    pub width: u32,
+    pub height: u32,
+    pub color_type: ColorType,
+    pub bit_depth: u8,
+}
+
+impl RawLayout {
+    pub fn insert_headers(&self, headers: &mut header::HeaderMap) {
+        let number = |n: u32| HeaderValue::from(n);
+
+        headers.insert(X_IMAGE_WIDTH, number(self.width));
+        headers.insert(X_IMAGE_HEIGHT, number(self.height));
+        headers.insert(
+            X_IMAGE_COLOR_TYPE,
+            HeaderValue::from_static(self.color_type.name()),
+        );
+        headers.insert(X_IMAGE_BIT_DEPTH, number(self.bit_depth as u32));
+    }
+}
+
+/// Sends an encoded image, spilling it to an anonymous temp file first when it's larger
+/// than `OUTPUT_SPILL_THRESHOLD`. The file-backed body is read in chunks, so a slow client
+/// downloading a huge result doesn't pin the whole buffer in memory.
+///
+/// Uncompressed formats are gzipped for clients that accept it.
+pub fn respond(
+    out: Vec<u8>,
+    content_type: &str,
+    config: &Config,
+    req: &HttpRequest,
+) -> io::Result<HttpResponse> {
+    let compressible = COMPRESSIBLE_IMAGES.contains(&content_type);
+    let gzip = compressible && accepts_gzip(req);
+
+    let encoding = match gzip {
+        true => ContentEncoding::Gzip,
+        false => ContentEncoding::Identity,
+    };
+
+    let mut res = if out.len() <= config.output_spill_threshold {
+        let body = match gzip {
+            true => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(&out)?;
+                encoder.finish()?
+            }
+            false => out,
+        };
+
+        let mut res = HttpResponse::Ok().content_type(content_type).body(body);
+
+        if gzip || INCOMPRESSIBLE.contains(&content_type) {
+            res.headers_mut().insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.as_str()),
+            );
+        }
+
+        res
+    } else {
+        let mut file = tempfile::tempfile_in(&config.temp_dir)?;
+
+        match gzip {
+            true => {
+                let mut encoder = GzEncoder::new(&mut file, Compression::fast());
+                encoder.write_all(&out)?;
+                encoder.finish()?;
+            }
+            false => file.write_all(&out)?,
+        }
+
+        drop(out);
+
+        let file = NamedFile::from_file(file, "output")?
+            .set_content_type(
+                content_type
+                    .parse()
+                    .unwrap_or(mime::APPLICATION_OCTET_STREAM),
+            )
+            .set_content_encoding(encoding)
+            .disable_content_disposition()
+            .use_etag(false)
+            .use_last_modified(false);
+
+        file.into_response(req)
+    };
+
+    if compressible {
+        res.headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+
+    Ok(res)
+}
+
+fn accepts_gzip(req: &HttpRequest) -> bool {
+    AcceptEncoding::parse(req).is_ok_and(|accept| {
+        accept.negotiate([Encoding::gzip(), Encoding::identity()].iter()) == Some(Encoding::gzip())
+    })
+}
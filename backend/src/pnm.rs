@@ -0,0 +1,226 @@
+use anyhow::{bail, ensure, Context};
+
+use crate::{ensure_valid_dimensions, ne_to_be_16, ColorType, Decoded, Error, Format, Metadata};
+
+/// The fields of a netpbm header (P1-P6).
+pub struct Header {
+    /// The digit after the `P` in the magic number.
+    kind: u8,
+    pub width: u32,
+    pub height: u32,
+    /// Largest sample value; always 1 for bitmaps (P1/P4).
+    maxval: u32,
+}
+
+/// Cursor over a netpbm file, understanding its whitespace and `#` comment rules.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn skip_whitespace(&mut self) {
+        while let Some(&c) = self.buf.get(self.pos) {
+            match c {
+                b'#' => {
+                    while self.buf.get(self.pos).is_some_and(|&c| c != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                c if c.is_ascii_whitespace() => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+
+    fn number(&mut self) -> anyhow::Result<u32> {
+        self.skip_whitespace();
+
+        let start = self.pos;
+
+        while self.buf.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+
+        std::str::from_utf8(&self.buf[start..self.pos])?
+            .parse()
+            .context("PNM: expected a number")
+    }
+
+    /// Reads one digit of a plain bitmap, where samples needn't be separated.
+    fn bit(&mut self) -> anyhow::Result<bool> {
+        self.skip_whitespace();
+
+        let bit = match self.buf.get(self.pos) {
+            Some(b'0') => false,
+            Some(b'1') => true,
+            _ => bail!("PNM: expected a 0 or 1"),
+        };
+
+        self.pos += 1;
+
+        Ok(bit)
+    }
+
+    fn header(&mut self) -> anyhow::Result<Header> {
+        let kind = match self.buf.get(..2) {
+            Some([b'P', kind @ b'1'..=b'6']) => kind - b'0',
+            _ => bail!(Error::CouldNotReadInfo(Format::Pnm)),
+        };
+        self.pos = 2;
+
+        let width = self.number()?;
+        let height = self.number()?;
+        let maxval = match kind {
+            1 | 4 => 1,
+            _ => self.number()?,
+        };
+
+        ensure!(
+            (1..=65535).contains(&maxval),
+            "PNM: invalid maximum value {maxval}"
+        );
+
+        // Binary rasters start after exactly one whitespace character.
+        if kind >= 4 {
+            self.pos += 1;
+        }
+
+        Ok(Header {
+            kind,
+            width,
+            height,
+            maxval,
+        })
+    }
+}
+
+pub fn header(buf: &[u8]) -> anyhow::Result<Header> {
+    Reader {
+        buf,
+        pos: 0,
+    }
+    .header()
+}
+
+pub fn decode(buf: &[u8], max_pixels: u64) -> anyhow::Result<Decoded> {
+    let mut reader = Reader {
+        buf,
+        pos: 0,
+    };
+    let Header {
+        kind,
+        width,
+        height,
+        maxval,
+    } = reader.header()?;
+
+    ensure_valid_dimensions(Format::Pnm, width, height, max_pixels)?;
+
+    let channels = match kind {
+        3 | 6 => 3,
+        _ => 1,
+    };
+    let samples = width as usize * height as usize * channels;
+
+    // Bitmaps use 1 for black, the opposite of every other sample.
+    let bit_to_gray = |bit: bool| if bit { 0 } else { 255 };
+
+    let values: Vec<u32> = match kind {
+        1 => (0..samples)
+            .map(|_| reader.bit().map(bit_to_gray))
+            .collect::<anyhow::Result<_>>()?,
+        2 | 3 => (0..samples)
+            .map(|_| reader.number())
+            .collect::<anyhow::Result<_>>()?,
+        // Each row is packed MSB-first and padded out to a whole byte.
+        4 => {
+            let row_bytes = (width as usize).div_ceil(8);
+            let raster = buf
+                .get(reader.pos..reader.pos + row_bytes * height as usize)
+                .context("PNM: raster is truncated")?;
+
+            raster
+                .chunks_exact(row_bytes.max(1))
+                .flat_map(|row| {
+                    (0..width as usize).map(|x| bit_to_gray(row[x / 8] >> (7 - x % 8) & 1 == 1))
+                })
+                .collect()
+        }
+        _ => {
+            let sample_bytes = if maxval > 255 { 2 } else { 1 };
+            let raster = buf
+                .get(reader.pos..reader.pos + samples * sample_bytes)
+                .context("PNM: raster is truncated")?;
+
+            match sample_bytes {
+                2 => raster
+                    .chunks_exact(2)
+                    .map(|s| u16::from_be_bytes([s[0], s[1]]) as u32)
+                    .collect(),
+                _ => raster.iter().map(|&s| s as u32).collect(),
+            }
+        }
+    };
+
+    // Bitmaps were already expanded to 0/255 above.
+    let maxval = if matches!(kind, 1 | 4) { 255 } else { maxval };
+    let (bit_depth, max) = if maxval > 255 { (16, 65535) } else { (8, 255) };
+    let scale = |v: u32| ((v.min(maxval) as u64 * max + maxval as u64 / 2) / maxval as u64) as u16;
+
+    Ok(Decoded {
+        bytes: match bit_depth {
+            16 => values
+                .iter()
+                .flat_map(|&v| scale(v).to_ne_bytes())
+                .collect(),
+            _ => values.iter().map(|&v| scale(v) as u8).collect(),
+        },
+        color_type: match channels {
+            3 => ColorType::Rgb,
+            _ => ColorType::Grayscale,
+        },
+        width,
+        height,
+        bit_depth,
+        metadata: Metadata::default(),
+    })
+}
+
+/// Writes a binary graymap (P5) or pixmap (P6), keeping 16-bit samples.
+pub fn encode(image: &Decoded) -> anyhow::Result<Vec<u8>> {
+    let kind = match image.color_type {
+        ColorType::Grayscale => 5,
+        ColorType::Rgb => 6,
+
+        c => bail!(Error::UnsupportedColorType(Format::Pnm, format!("{c:?}"))),
+    };
+    let maxval = match image.bit_depth {
+        16 => 65535,
+        _ => 255,
+    };
+
+    let mut out = format!("P{kind}\n{} {}\n{maxval}\n", image.width, image.height).into_bytes();
+
+    match image.bit_depth {
+        16 => out.extend(ne_to_be_16(&image.bytes)),
+        _ => out.extend_from_slice(&image.bytes),
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitmaps_unpack_to_black_and_white() {
+        // A 4x2 checkerboard, each row padded out to a byte.
+        let decoded = decode(b"P4\n4 2\n\xA0\x50", u64::MAX).unwrap();
+
+        assert_eq!(decoded.color_type, ColorType::Grayscale);
+        assert_eq!(decoded.bit_depth, 8);
+        assert_eq!(decoded.bytes, [0, 255, 0, 255, 255, 0, 255, 0]);
+    }
+}
@@ -0,0 +1,177 @@
+use crate::Format;
+
+/// Standard RGB color spaces that outputs can be tagged with via `assign_profile`.
+#[derive(Debug, Clone, Copy)]
+pub enum NamedProfile {
+    Srgb,
+    DisplayP3,
+    Rec2020,
+}
+
+/// D50 white, the ICC profile connection space illuminant.
+const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+
+/// Bradford adaptation from D65 (the white point of every profile here) to D50.
+const D65_TO_D50: [[f64; 3]; 3] = [
+    [1.047886, 0.0229188, -0.0502161],
+    [0.0295818, 0.9904835, -0.0170787],
+    [-0.0092519, 0.0150726, 0.7516781],
+];
+
+/// sRGB transfer function as ICC parametric curve type 3: g, a, b, c, d.
+const SRGB_CURVE: [f64; 5] = [2.4, 1. / 1.055, 0.055 / 1.055, 1. / 12.92, 0.04045];
+/// BT.2020 transfer function (inverse of its OETF) in the same form.
+const REC2020_CURVE: [f64; 5] = [1. / 0.45, 1. / 1.0993, 0.0993 / 1.0993, 1. / 4.5, 0.081_243];
+
+impl NamedProfile {
+    pub fn from_name(name: &str) -> Option<NamedProfile> {
+        match name {
+            "srgb" => Some(NamedProfile::Srgb),
+            "display-p3" => Some(NamedProfile::DisplayP3),
+            "rec2020" => Some(NamedProfile::Rec2020),
+
+            _ => None,
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            NamedProfile::Srgb => "sRGB",
+            NamedProfile::DisplayP3 => "Display P3",
+            NamedProfile::Rec2020 => "Rec. 2020",
+        }
+    }
+
+    /// Red, green and blue colorants: the primaries' XYZ, adapted to D50.
+    fn colorants(&self) -> [[f64; 3]; 3] {
+        match self {
+            NamedProfile::Srgb => [
+                [0.436041, 0.222485, 0.013920],
+                [0.385113, 0.716905, 0.097067],
+                [0.143046, 0.060610, 0.713913],
+            ],
+            NamedProfile::DisplayP3 => [
+                [0.515119, 0.241189, -0.001050],
+                [0.291978, 0.692244, 0.041879],
+                [0.157103, 0.066567, 0.784071],
+            ],
+            NamedProfile::Rec2020 => [
+                [0.673480, 0.279043, -0.001933],
+                [0.165671, 0.675344, 0.029983],
+                [0.125049, 0.045613, 0.796851],
+            ],
+        }
+    }
+
+    fn curve(&self) -> [f64; 5] {
+        match self {
+            NamedProfile::Srgb | NamedProfile::DisplayP3 => SRGB_CURVE,
+            NamedProfile::Rec2020 => REC2020_CURVE,
+        }
+    }
+
+    /// Builds a minimal ICC v4 matrix/TRC display profile for this color space.
+    pub fn icc(&self) -> Vec<u8> {
+        let [red, green, blue] = self.colorants();
+        let curve = para(self.curve());
+
+        let tags: [(&[u8; 4], Vec<u8>); 10] = [
+            (b"desc", mluc(self.description())),
+            (b"cprt", mluc("No copyright, use freely")),
+            (b"wtpt", xyz(D50)),
+            (b"chad", sf32(D65_TO_D50)),
+            (b"rXYZ", xyz(red)),
+            (b"gXYZ", xyz(green)),
+            (b"bXYZ", xyz(blue)),
+            (b"rTRC", curve.clone()),
+            (b"gTRC", curve.clone()),
+            (b"bTRC", curve),
+        ];
+
+        const HEADER_LEN: usize = 128;
+
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let mut data = Vec::new();
+        let data_start = HEADER_LEN + 4 + tags.len() * 12;
+
+        for (signature, tag) in &tags {
+            table.extend_from_slice(*signature);
+            table.extend(((data_start + data.len()) as u32).to_be_bytes());
+            table.extend((tag.len() as u32).to_be_bytes());
+
+            data.extend(tag);
+            // Tags start on 4-byte boundaries.
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
+
+        let size = data_start + data.len();
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend((size as u32).to_be_bytes());
+        header.extend([0; 4]); // Preferred CMM
+        header.extend([4, 0x30, 0, 0]); // Version 4.3
+        header.extend(b"mntrRGB XYZ ");
+        header.extend([0; 12]); // Creation date
+        header.extend(b"acsp");
+        header.extend([0; 24]); // Platform, flags, manufacturer, model, attributes
+        header.extend([0; 4]); // Perceptual rendering intent
+        header.extend(&xyz(D50)[8..]);
+        header.resize(HEADER_LEN, 0); // Creator, profile ID and reserved bytes
+
+        [header, table, data].concat()
+    }
+}
+
+impl Format {
+    /// Whether our encoder for this format can embed an ICC profile.
+    pub fn supports_icc_profile(&self) -> bool {
+        matches!(self, Format::Png | Format::Jpeg | Format::WebP)
+    }
+}
+
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.).round() as i32).to_be_bytes()
+}
+
+fn xyz(value: [f64; 3]) -> Vec<u8> {
+    [*b"XYZ ", [0; 4]]
+        .into_iter()
+        .chain(value.map(s15_fixed16))
+        .flatten()
+        .collect()
+}
+
+fn sf32(matrix: [[f64; 3]; 3]) -> Vec<u8> {
+    [*b"sf32", [0; 4]]
+        .into_iter()
+        .chain(matrix.into_iter().flatten().map(s15_fixed16))
+        .flatten()
+        .collect()
+}
+
+fn para(params: [f64; 5]) -> Vec<u8> {
+    // Function type 3 followed by a reserved u16.
+    [*b"para", [0; 4], [0, 3, 0, 0]]
+        .into_iter()
+        .chain(params.map(s15_fixed16))
+        .flatten()
+        .collect()
+}
+
+/// A single en-US record of ICC's multi-localized Unicode type.
+fn mluc(text: &str) -> Vec<u8> {
+    let utf16: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+
+    [
+        *b"mluc",
+        [0; 4],
+        1u32.to_be_bytes(),
+        12u32.to_be_bytes(),
+        *b"enUS",
+    ]
+    .into_iter()
+    .chain([(utf16.len() as u32).to_be_bytes(), 28u32.to_be_bytes()])
+    .flatten()
+    .chain(utf16)
+    .collect()
+}
@@ -0,0 +1,44 @@
+use std::future::Future;
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+};
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Incoming IDs longer than this are ignored in favor of a generated one.
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The ID of the request currently being handled, if called from within one.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Reuses the client's `X-Request-Id` when it's sane, otherwise generates a fresh UUID.
+pub fn from_request(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_LEN)
+        .map_or_else(|| uuid::Uuid::new_v4().to_string(), String::from)
+}
+
+/// Runs the rest of the request with `id` available to `current`, and echoes it back
+/// in the response headers.
+pub async fn scope<B>(
+    id: String,
+    response: impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    let mut response = REQUEST_ID.scope(id.clone(), response).await?;
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(X_REQUEST_ID, value);
+    }
+
+    Ok(response)
+}
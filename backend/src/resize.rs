@@ -0,0 +1,477 @@
+use anyhow::Context;
+use resize::{Pixel, Type};
+use rgb::{FromSlice, RGBA, RGBA8};
+
+use crate::{histogram::luma, ColorType, Decoded};
+
+/// How an image is fit into a box when both a width and a height are requested.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fit {
+    /// Stretch to exactly the box, ignoring aspect ratio.
+    Exact,
+    /// Scale to fit inside the box, then pad the rest with the background.
+    Contain,
+    /// Scale to fill the box, then crop the overflow evenly from both sides.
+    Cover,
+}
+
+impl Fit {
+    pub fn from_name(name: &str) -> Option<Fit> {
+        match name {
+            "exact" => Some(Fit::Exact),
+            "contain" => Some(Fit::Contain),
+            "cover" => Some(Fit::Cover),
+
+            _ => None,
+        }
+    }
+}
+
+/// Parses `#rrggbb` or `#rrggbbaa`, with the `#` optional.
+pub fn parse_hex_color(hex: &str) -> Option<RGBA8> {
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok();
+
+    Some(RGBA::new(
+        channel(0)?,
+        channel(1)?,
+        channel(2)?,
+        match hex.len() {
+            8 => channel(3)?,
+            _ => 255,
+        },
+    ))
+}
+
+impl Decoded {
+    /// Resamples the image to exactly `width`x`height`.
+    pub fn resize(&mut self, width: u32, height: u32) -> anyhow::Result<()> {
+        if (width, height) == (self.width, self.height) {
+            return Ok(());
+        }
+
+        let src = (self.width as usize, self.height as usize);
+        let dst = (width as usize, height as usize);
+
+        let out = match self.bit_depth {
+            16 => {
+                let samples: Vec<u16> = self
+                    .bytes
+                    .chunks_exact(2)
+                    .map(|s| u16::from_ne_bytes([s[0], s[1]]))
+                    .collect();
+
+                resample16(&self.color_type, &samples, src, dst)?
+                    .iter()
+                    .flat_map(|s| s.to_ne_bytes())
+                    .collect()
+            }
+            _ => resample8(&self.color_type, &self.bytes, src, dst)?,
+        };
+
+        self.bytes = out;
+        self.width = width;
+        self.height = height;
+
+        Ok(())
+    }
+
+    /// Scales the image down, preserving aspect ratio, so neither side exceeds `max`.
+    /// Images already within bounds are left untouched.
+    pub fn fit_within(&mut self, max: u32) -> anyhow::Result<()> {
+        if self.width <= max && self.height <= max {
+            return Ok(());
+        }
+
+        let scale = max as f64 / self.width.max(self.height) as f64;
+
+        let width = ((self.width as f64 * scale).round() as u32).clamp(1, max);
+        let height = ((self.height as f64 * scale).round() as u32).clamp(1, max);
+
+        self.resize(width, height)
+    }
+
+    /// Resamples non-square pixels to square ones by stretching the image along the
+    /// pixels' longer side, reduced if needed so neither side exceeds `max`.
+    pub fn correct_pixel_aspect(&mut self, max: u32) -> anyhow::Result<()> {
+        let Some(aspect) = self.metadata.pixel_aspect.take() else {
+            return Ok(());
+        };
+
+        let (mut width, mut height) = (self.width as f64, self.height as f64);
+
+        match aspect > 1. {
+            true => width *= aspect,
+            false => height /= aspect,
+        }
+
+        let factor = (max as f64 / width.max(height)).min(1.);
+
+        self.resize(
+            ((width * factor).round() as u32).clamp(1, max),
+            ((height * factor).round() as u32).clamp(1, max),
+        )
+    }
+
+    /// Scales both sides by `factor`, reduced if needed so neither side exceeds `max`.
+    pub fn scale(&mut self, factor: f64, max: u32) -> anyhow::Result<()> {
+        let factor = factor.min(max as f64 / self.width.max(self.height) as f64);
+
+        let width = ((self.width as f64 * factor).round() as u32).clamp(1, max);
+        let height = ((self.height as f64 * factor).round() as u32).clamp(1, max);
+
+        self.resize(width, height)
+    }
+
+    /// Resizes to the requested dimensions, each of which must be at most `max`. With only
+    /// one given, the other follows the aspect ratio; with both, `fit` decides how the
+    /// image fills the box. Sides derived from the aspect ratio are capped at `max` too,
+    /// so a sliver of an image can't ask for an enormous buffer.
+    pub fn fit_to(
+        &mut self,
+        width: Option<u32>,
+        height: Option<u32>,
+        fit: Fit,
+        background: RGBA8,
+        max: u32,
+    ) -> anyhow::Result<()> {
+        let aspect = self.width as f64 / self.height as f64;
+
+        let (width, height) = match (width, height) {
+            (Some(width), Some(height)) => (width, height),
+            (Some(width), None) => {
+                return self.resize(
+                    width,
+                    ((width as f64 / aspect).round() as u32).clamp(1, max),
+                )
+            }
+            (None, Some(height)) => {
+                return self.resize(
+                    ((height as f64 * aspect).round() as u32).clamp(1, max),
+                    height,
+                )
+            }
+            (None, None) => return Ok(()),
+        };
+
+        let scale_x = width as f64 / self.width as f64;
+        let scale_y = height as f64 / self.height as f64;
+
+        match fit {
+            Fit::Exact => self.resize(width, height),
+            Fit::Contain => {
+                let scale = scale_x.min(scale_y);
+
+                self.resize(
+                    ((self.width as f64 * scale).round() as u32).clamp(1, width),
+                    ((self.height as f64 * scale).round() as u32).clamp(1, height),
+                )?;
+                self.pad(width, height, background);
+
+                Ok(())
+            }
+            // Cropping the source to the box's proportions first means both axes scale
+            // alike, and nothing larger than the box is ever allocated.
+            Fit::Cover => {
+                let scale = scale_x.max(scale_y);
+
+                let crop_width = ((width as f64 / scale).round() as u32).clamp(1, self.width);
+                let crop_height = ((height as f64 / scale).round() as u32).clamp(1, self.height);
+
+                self.crop(
+                    ((self.width - crop_width) / 2) as usize,
+                    ((self.height - crop_height) / 2) as usize,
+                    crop_width as usize,
+                    crop_height as usize,
+                );
+
+                self.resize(width, height)
+            }
+        }
+    }
+
+    /// Centers the image on a `width`x`height` canvas filled with `background`, gaining an
+    /// alpha channel if the background is translucent.
+    pub fn pad(&mut self, width: u32, height: u32, background: RGBA8) {
+        self.convert_to_rgb();
+
+        if background.a < 255 && !self.color_type.has_alpha() {
+            self.convert_color_type(match self.color_type {
+                ColorType::Grayscale => ColorType::GrayscaleAlpha,
+                _ => ColorType::Rgba,
+            });
+        }
+
+        let RGBA { r, g, b, a } = background;
+
+        let fill = match self.color_type {
+            ColorType::Grayscale => vec![luma(r, g, b)],
+            ColorType::GrayscaleAlpha => vec![luma(r, g, b), a],
+            ColorType::Rgba => vec![r, g, b, a],
+            _ => vec![r, g, b],
+        };
+        let fill: Vec<u8> = match self.bit_depth {
+            16 => fill
+                .iter()
+                .flat_map(|&s| (s as u16 * 257).to_ne_bytes())
+                .collect(),
+            _ => fill,
+        };
+
+        let bpp = self.bytes_per_pixel();
+        let (width, height) = (width as usize, height as usize);
+        let row = self.width as usize * bpp;
+        let left = (width - self.width as usize) / 2;
+        let top = (height - self.height as usize) / 2;
+
+        let mut out = fill.repeat(width * height);
+
+        for (y, src) in self.bytes.chunks_exact(row).enumerate() {
+            let dst = ((top + y) * width + left) * bpp;
+
+            out[dst..dst + row].copy_from_slice(src);
+        }
+
+        self.bytes = out;
+        self.width = width as u32;
+        self.height = height as u32;
+    }
+}
+
+type Dimensions = (usize, usize);
+
+const FILTER: Type = Type::Lanczos3;
+
+fn resample8(
+    color_type: &ColorType,
+    src: &[u8],
+    (src_w, src_h): Dimensions,
+    (dst_w, dst_h): Dimensions,
+) -> anyhow::Result<Vec<u8>> {
+    let mut out = vec![0; dst_w * dst_h * color_type.channels()];
+
+    match color_type {
+        ColorType::Grayscale => resize::new(src_w, src_h, dst_w, dst_h, Pixel::Gray8, FILTER)?
+            .resize(src.as_gray(), out.as_gray_mut()),
+        ColorType::Rgb | ColorType::YCbCr => {
+            resize::new(src_w, src_h, dst_w, dst_h, Pixel::RGB8, FILTER)?
+                .resize(src.as_rgb(), out.as_rgb_mut())
+        }
+        ColorType::Rgba => resize::new(src_w, src_h, dst_w, dst_h, Pixel::RGBA8P, FILTER)?
+            .resize(src.as_rgba(), out.as_rgba_mut()),
+        // CMYK has no alpha, so each channel is scaled independently.
+        ColorType::Cmyk => resize::new(src_w, src_h, dst_w, dst_h, Pixel::RGBA8, FILTER)?
+            .resize(src.as_rgba(), out.as_rgba_mut()),
+        // There's no gray+alpha pixel format, so go through premultiplied RGBA.
+        ColorType::GrayscaleAlpha => {
+            let src: Vec<RGBA8> = src
+                .chunks_exact(2)
+                .map(|px| RGBA::new(px[0], px[0], px[0], px[1]))
+                .collect();
+            let mut dst = vec![RGBA::default(); dst_w * dst_h];
+
+            resize::new(src_w, src_h, dst_w, dst_h, Pixel::RGBA8P, FILTER)?
+                .resize(&src, &mut dst)?;
+
+            out = dst.iter().flat_map(|px| [px.r, px.a]).collect();
+
+            Ok(())
+        }
+    }
+    .context("Failed to resize image")?;
+
+    Ok(out)
+}
+
+fn resample16(
+    color_type: &ColorType,
+    src: &[u16],
+    (src_w, src_h): Dimensions,
+    (dst_w, dst_h): Dimensions,
+) -> anyhow::Result<Vec<u16>> {
+    let mut out = vec![0; dst_w * dst_h * color_type.channels()];
+
+    match color_type {
+        ColorType::Grayscale => resize::new(src_w, src_h, dst_w, dst_h, Pixel::Gray16, FILTER)?
+            .resize(src.as_gray(), out.as_gray_mut()),
+        ColorType::Rgb | ColorType::YCbCr => {
+            resize::new(src_w, src_h, dst_w, dst_h, Pixel::RGB16, FILTER)?
+                .resize(src.as_rgb(), out.as_rgb_mut())
+        }
+        ColorType::Rgba => resize::new(src_w, src_h, dst_w, dst_h, Pixel::RGBA16P, FILTER)?
+            .resize(src.as_rgba(), out.as_rgba_mut()),
+        ColorType::Cmyk => resize::new(src_w, src_h, dst_w, dst_h, Pixel::RGBA16, FILTER)?
+            .resize(src.as_rgba(), out.as_rgba_mut()),
+        ColorType::GrayscaleAlpha => {
+            let src: Vec<RGBA<u16>> = src
+                .chunks_exact(2)
+                .map(|px| RGBA::new(px[0], px[0], px[0], px[1]))
+                .collect();
+            let mut dst = vec![RGBA::default(); dst_w * dst_h];
+
+            resize::new(src_w, src_h, dst_w, dst_h, Pixel::RGBA16P, FILTER)?
+                .resize(&src, &mut dst)?;
+
+            out = dst.iter().flat_map(|px| [px.r, px.a]).collect();
+
+            Ok(())
+        }
+    }
+    .context("Failed to resize image")?;
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Metadata;
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const GREEN: [u8; 3] = [0, 255, 0];
+    const BLUE: RGBA8 = RGBA8::new(0, 0, 255, 255);
+
+    /// A solid red 4:3 image.
+    fn four_by_three() -> Decoded {
+        Decoded {
+            bytes: RED.repeat(40 * 30),
+            color_type: ColorType::Rgb,
+            width: 40,
+            height: 30,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        }
+    }
+
+    /// A 400x300 image in columns: blue for the outer tenth on each side, which a
+    /// centered 1:1 crop removes, and red then green for the two halves of the rest.
+    fn columns() -> Decoded {
+        Decoded {
+            bytes: (0..300)
+                .flat_map(|_| {
+                    (0..400).flat_map(|x| match x {
+                        ..40 | 360.. => [0, 0, 255],
+                        ..200 => RED,
+                        _ => GREEN,
+                    })
+                })
+                .collect(),
+            color_type: ColorType::Rgb,
+            width: 400,
+            height: 300,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        }
+    }
+
+    fn pixel(image: &Decoded, x: u32, y: u32) -> &[u8] {
+        let at = (y * image.width + x) as usize * 3;
+
+        &image.bytes[at..at + 3]
+    }
+
+    /// Which of red, green and blue is strongest, since resampling blurs the boundaries.
+    fn dominant(image: &Decoded, x: u32, y: u32) -> usize {
+        let px = pixel(image, x, y);
+
+        (0..3).max_by_key(|&c| px[c]).unwrap()
+    }
+
+    #[test]
+    fn exact_stretches_to_the_box() {
+        let mut image = columns();
+        image
+            .fit_to(Some(20), Some(20), Fit::Exact, BLUE, 1000)
+            .unwrap();
+
+        // Every column survives, squeezed horizontally.
+        assert_eq!((image.width, image.height), (20, 20));
+        assert_eq!(
+            (0..20).map(|x| dominant(&image, x, 10)).collect::<Vec<_>>(),
+            [&[2; 2][..], &[0; 8], &[1; 8], &[2; 2]].concat()
+        );
+    }
+
+    #[test]
+    fn contain_pads_with_the_background() {
+        let mut image = four_by_three();
+        image
+            .fit_to(Some(20), Some(20), Fit::Contain, BLUE, 1000)
+            .unwrap();
+
+        // Scaled to 20x15, with the rest split between the top and bottom.
+        assert_eq!((image.width, image.height), (20, 20));
+        assert_eq!(pixel(&image, 0, 0), [0, 0, 255]);
+        assert_eq!(pixel(&image, 10, 10), RED);
+        assert_eq!(pixel(&image, 19, 19), [0, 0, 255]);
+    }
+
+    #[test]
+    fn cover_crops_the_overflow() {
+        let mut image = columns();
+        image
+            .fit_to(Some(20), Some(20), Fit::Cover, BLUE, 1000)
+            .unwrap();
+
+        // The blue edges are cropped off, and the halves keep their proportions.
+        assert_eq!((image.width, image.height), (20, 20));
+        assert_eq!(
+            (0..20).map(|x| dominant(&image, x, 10)).collect::<Vec<_>>(),
+            [[0; 10], [1; 10]].concat()
+        );
+    }
+
+    #[test]
+    fn cover_keeps_extreme_proportions() {
+        // A 1000x10 strip into a box the size of the cap: scaling each axis separately to
+        // fit under the cap would squash it.
+        let mut strip = Decoded {
+            bytes: (0..10)
+                .flat_map(|_| (0..1000).flat_map(|x| if x < 500 { RED } else { GREEN }))
+                .collect(),
+            color_type: ColorType::Rgb,
+            width: 1000,
+            height: 10,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        };
+        strip
+            .fit_to(Some(1000), Some(1000), Fit::Cover, BLUE, 1000)
+            .unwrap();
+
+        // Only the middle 10x10 of the strip is left, split down the middle.
+        assert_eq!((strip.width, strip.height), (1000, 1000));
+        assert_eq!(dominant(&strip, 100, 500), 0);
+        assert_eq!(dominant(&strip, 900, 500), 1);
+    }
+
+    #[test]
+    fn derived_sides_are_capped() {
+        let mut sliver = Decoded {
+            bytes: RED.repeat(10_000),
+            color_type: ColorType::Rgb,
+            width: 1,
+            height: 10_000,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        };
+        sliver
+            .fit_to(Some(100), None, Fit::Contain, BLUE, 1000)
+            .unwrap();
+
+        assert_eq!((sliver.width, sliver.height), (100, 1000));
+
+        let mut sliver = four_by_three();
+        sliver.resize(1, 300).unwrap();
+        sliver
+            .fit_to(Some(500), Some(500), Fit::Cover, BLUE, 1000)
+            .unwrap();
+
+        assert_eq!((sliver.width, sliver.height), (500, 500));
+    }
+}
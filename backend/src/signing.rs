@@ -0,0 +1,195 @@
+use std::{
+    future::Future,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::Method,
+    HttpResponse,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Paths reachable without a signature, so orchestrators can probe health.
+const UNSIGNED_PATHS: &[&str] = &["/readyz"];
+
+/// The only `POST` endpoint whose options all travel in the query string, and so are
+/// covered by the signature. The others take theirs from a multipart body, which a signed
+/// URL could be replayed with any contents of.
+const SIGNABLE_POST_PATH: &str = "/convert_raw";
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Compares every byte regardless, so the time taken doesn't leak the matching prefix.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Checks the `signature` and `expires` query parameters of `req`.
+///
+/// The signature is the hex HMAC-SHA256, keyed with `SIGNING_SECRET`, of the method, the
+/// path and the query string without `signature`, joined by newlines, e.g.
+/// `POST\n/convert_raw\noutput_type=png&expires=1760000000`. `expires` is a Unix
+/// timestamp in seconds.
+fn verify(secret: &[u8], req: &ServiceRequest) -> Result<(), HttpResponse> {
+    if req.method() == Method::POST && req.path() != SIGNABLE_POST_PATH {
+        return Err(HttpResponse::Forbidden().body(format!(
+            "Signed requests can only convert through {SIGNABLE_POST_PATH}, whose options \
+             are all covered by the signature"
+        )));
+    }
+
+    let (mut signature, mut expires, mut signed) = (None, None, Vec::new());
+
+    for pair in req.query_string().split('&').filter(|p| !p.is_empty()) {
+        match pair.split_once('=') {
+            Some(("signature", value)) => signature = Some(value),
+            Some(("expires", value)) => {
+                expires = Some(value);
+                signed.push(pair);
+            }
+            _ => signed.push(pair),
+        }
+    }
+
+    let (Some(signature), Some(expires)) = (signature, expires) else {
+        return Err(HttpResponse::Unauthorized().body("Missing signature or expiry"));
+    };
+
+    let (Some(signature), Ok(expires)) = (decode_hex(signature), expires.parse::<u64>()) else {
+        return Err(HttpResponse::Unauthorized().body("Malformed signature or expiry"));
+    };
+
+    let message = format!("{}\n{}\n{}", req.method(), req.path(), signed.join("&"));
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(message.as_bytes());
+
+    if mac.verify_slice(&signature).is_err() {
+        return Err(HttpResponse::Unauthorized().body("Invalid signature"));
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+
+    if expires <= now {
+        return Err(HttpResponse::Forbidden().body("Signature has expired"));
+    }
+
+    Ok(())
+}
+
+/// Rejects requests without a valid, unexpired signature when `SIGNING_SECRET` is set,
+/// with a 401 for missing or wrong signatures and a 403 for expired ones. Lets everything
+/// through otherwise.
+///
+/// Since only the URL is signed, multipart endpoints are refused outright while signing
+/// is on: conversions go through `/convert_raw`, and the `GET` endpoints stay available.
+pub fn guard<S, B>(
+    secret: Option<&str>,
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<EitherBody<B>>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let rejection = secret
+        .filter(|_| !UNSIGNED_PATHS.contains(&req.path()))
+        .and_then(|secret| verify(secret.as_bytes(), &req).err());
+
+    let call = match rejection {
+        Some(rejection) => Err(req.into_response(rejection)),
+        None => Ok(srv.call(req)),
+    };
+
+    async move {
+        match call {
+            Ok(response) => response.await.map(ServiceResponse::map_into_left_body),
+            Err(rejected) => Ok(rejected.map_into_right_body()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::TestRequest};
+
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    fn sign(method: &str, path: &str, query: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET).unwrap();
+        mac.update(format!("{method}\n{path}\n{query}").as_bytes());
+
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    fn check(method: Method, uri: &str) -> Result<(), StatusCode> {
+        let req = TestRequest::default()
+            .method(method)
+            .uri(uri)
+            .to_srv_request();
+
+        verify(SECRET, &req).map_err(|res| res.status())
+    }
+
+    #[test]
+    fn accepts_only_intact_unexpired_signatures() {
+        let query = "output_type=png&expires=99999999999";
+        let signature = sign("POST", "/convert_raw", query);
+
+        assert_eq!(
+            check(
+                Method::POST,
+                &format!("/convert_raw?{query}&signature={signature}")
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                Method::POST,
+                &format!("/convert_raw?output_type=avif&expires=99999999999&signature={signature}")
+            ),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            check(Method::POST, &format!("/convert_raw?{query}")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+
+        let expired = sign("POST", "/convert_raw", "expires=1");
+        assert_eq!(
+            check(
+                Method::POST,
+                &format!("/convert_raw?expires=1&signature={expired}")
+            ),
+            Err(StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[test]
+    fn refuses_multipart_endpoints() {
+        let signature = sign("POST", "/convert_image", "expires=99999999999");
+
+        assert_eq!(
+            check(
+                Method::POST,
+                &format!("/convert_image?expires=99999999999&signature={signature}")
+            ),
+            Err(StatusCode::FORBIDDEN)
+        );
+    }
+}
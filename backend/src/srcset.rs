@@ -0,0 +1,212 @@
+use actix_multipart::form::{json::Json, tempfile::TempFile, MultipartForm};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+
+use crate::{
+    archive::{self, Archive},
+    config::Config,
+    decode_upload, disallowed_output, output_format, upload_stem, EncodeOptions,
+};
+
+/// Upper bound on the number of widths in one request, since each is a full encode.
+const MAX_WIDTHS: usize = 16;
+
+#[derive(Debug, MultipartForm)]
+pub struct SrcsetForm {
+    file: TempFile,
+    /// Target widths in pixels, e.g. `[320, 640, 1280]`. Widths beyond the source's are
+    /// skipped rather than upscaled.
+    widths: Json<Vec<u32>>,
+    output_type: Json<String>,
+}
+
+/// `manifest.json` in the returned archive.
+#[derive(Debug, Serialize)]
+struct Manifest {
+    /// Ready to drop into an `<img srcset>` attribute.
+    srcset: String,
+    images: Vec<Variant>,
+}
+
+#[derive(Debug, Serialize)]
+struct Variant {
+    file: String,
+    width: u32,
+    height: u32,
+    bytes: usize,
+}
+
+pub async fn srcset(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    MultipartForm(SrcsetForm {
+        file: input,
+        widths,
+        output_type,
+    }): MultipartForm<SrcsetForm>,
+) -> actix_web::Result<impl Responder, actix_web::Error> {
+    let Some(mut format) = output_format(&output_type) else {
+        return Ok(HttpResponse::BadRequest().body("Unsupported output type"));
+    };
+
+    if !config.allowed_output_formats.contains(format) {
+        return Ok(disallowed_output(format));
+    }
+
+    let mut widths = widths.into_inner();
+    widths.sort_unstable();
+    widths.dedup();
+
+    if widths.is_empty() || widths.len() > MAX_WIDTHS {
+        return Ok(HttpResponse::BadRequest()
+            .body(format!("widths must list between 1 and {MAX_WIDTHS} sizes")));
+    }
+
+    if widths[0] == 0 {
+        return Ok(HttpResponse::BadRequest().body("widths must be positive"));
+    }
+
+    let options = EncodeOptions::defaults(&config, format);
+
+    let stem = upload_stem(&input);
+
+    let mut source = match decode_upload(input, &config) {
+        Ok(decoded) => decoded,
+        Err(res) => return Ok(res),
+    };
+
+    // Responsive images are for display, so they should come out upright.
+    if let Some(orientation) = source.metadata.orientation() {
+        source.orient(orientation);
+    }
+
+    source.adapt_layout_for(&format);
+
+    let mut archive = Archive::new();
+    let mut images = Vec::new();
+
+    for &width in widths.iter().filter(|&&w| w <= source.width) {
+        let height =
+            ((source.height as f64 * width as f64 / source.width as f64).round() as u32).max(1);
+
+        let mut variant = source.clone();
+        variant
+            .resize(width, height)
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+
+        let out = format
+            .encode(&variant, &options)
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+
+        let file = format!("{stem}-{width}w.{}", format.extension());
+
+        archive
+            .add_image(&file, &out)
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+
+        images.push(Variant {
+            file,
+            width,
+            height,
+            bytes: out.len(),
+        });
+    }
+
+    if images.is_empty() {
+        return Ok(HttpResponse::BadRequest().body(format!(
+            "Every requested width exceeds the source's {}px",
+            source.width
+        )));
+    }
+
+    let manifest = Manifest {
+        srcset: images
+            .iter()
+            .map(|image| format!("{} {}w", image.file, image.width))
+            .collect::<Vec<_>>()
+            .join(", "),
+        images,
+    };
+
+    archive
+        .add_manifest(&manifest)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    let zip = archive
+        .finish()
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    archive::respond(zip, format!("{stem}-srcset.zip"), &config, &req)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, Write};
+
+    use actix_web::{body, test::TestRequest};
+
+    use super::*;
+    use crate::{ColorType, Decoded, Format, Metadata};
+
+    /// An ordinary photo-style JPEG, which mozjpeg decodes as YCbCr.
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let image = Decoded {
+            bytes: (0..width * height)
+                .flat_map(|i| [(i % 251) as u8, 100, 200])
+                .collect(),
+            color_type: ColorType::Rgb,
+            width,
+            height,
+            bit_depth: 8,
+            metadata: Metadata::default(),
+        };
+
+        Format::Jpeg
+            .encode(&image, &EncodeOptions::quick(90.))
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn jpegs_become_webp_variants() {
+        let config = Config::from_env().unwrap();
+
+        let mut file = tempfile::NamedTempFile::new_in(&config.temp_dir).unwrap();
+        file.write_all(&jpeg(1280, 720)).unwrap();
+        file.rewind().unwrap();
+
+        let form = SrcsetForm {
+            file: TempFile {
+                file,
+                content_type: Some(mime::IMAGE_JPEG),
+                file_name: Some("photo.jpg".to_string()),
+                size: 0,
+            },
+            widths: Json(vec![320, 640, 1280]),
+            output_type: Json("webp".to_string()),
+        };
+
+        let req = TestRequest::default().to_http_request();
+        let res = srcset(req.clone(), web::Data::new(config), MultipartForm(form))
+            .await
+            .unwrap()
+            .respond_to(&req);
+        assert!(res.status().is_success(), "{}", res.status());
+
+        let Ok(zip) = body::to_bytes(res.into_body()).await else {
+            panic!("couldn't read the archive");
+        };
+        let mut zip = zip::ZipArchive::new(Cursor::new(zip)).unwrap();
+
+        for (width, height) in [(320, 180), (640, 360), (1280, 720)] {
+            let mut webp = Vec::new();
+            zip.by_name(&format!("photo-{width}w.webp"))
+                .unwrap()
+                .read_to_end(&mut webp)
+                .unwrap();
+
+            let decoded = Format::WebP.decode(Cursor::new(webp), u64::MAX).unwrap();
+            assert_eq!(decoded.color_type, ColorType::Rgb);
+            assert_eq!((decoded.width, decoded.height), (width, height));
+        }
+    }
+}
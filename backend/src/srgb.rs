@@ -0,0 +1,122 @@
+use lcms2::{Flags, Intent, PixelFormat, Profile, Transform};
+
+use crate::{ColorType, Decoded};
+
+impl Decoded {
+    /// Converts the pixels from their embedded ICC profile into sRGB, at their own bit
+    /// depth. Images without a profile are assumed to already be sRGB, and grayscale images
+    /// are left as-is.
+    pub fn convert_to_srgb(&mut self) {
+        if matches!(
+            self.color_type,
+            ColorType::Grayscale | ColorType::GrayscaleAlpha
+        ) {
+            return;
+        }
+
+        let Some(icc) = self.metadata.icc_profile.take() else {
+            return;
+        };
+
+        let Ok(source) = Profile::new_icc(&icc) else {
+            // An unparseable profile can't be honored either way, so treat the pixels as sRGB.
+            return;
+        };
+
+        let wide = self.bit_depth == 16;
+
+        let (input, output, color_type) = match (self.color_type, wide) {
+            (ColorType::Rgb, false) => (PixelFormat::RGB_8, PixelFormat::RGB_8, ColorType::Rgb),
+            (ColorType::Rgb, true) => (PixelFormat::RGB_16, PixelFormat::RGB_16, ColorType::Rgb),
+            (ColorType::Rgba, false) => (PixelFormat::RGBA_8, PixelFormat::RGBA_8, ColorType::Rgba),
+            (ColorType::Rgba, true) => {
+                (PixelFormat::RGBA_16, PixelFormat::RGBA_16, ColorType::Rgba)
+            }
+            (ColorType::Cmyk, false) => (PixelFormat::CMYK_8, PixelFormat::RGB_8, ColorType::Rgb),
+            (ColorType::Cmyk, true) => (PixelFormat::CMYK_16, PixelFormat::RGB_16, ColorType::Rgb),
+            (ColorType::YCbCr, _) => {
+                self.convert_to_rgb();
+                self.metadata.icc_profile = Some(icc);
+                return self.convert_to_srgb();
+            }
+            (ColorType::Grayscale | ColorType::GrayscaleAlpha, _) => unreachable!("returned above"),
+        };
+
+        // Samples are native-endian, as lcms expects.
+        let transform = Transform::<u8, u8>::new_flags(
+            &source,
+            input,
+            &Profile::new_srgb(),
+            output,
+            Intent::Perceptual,
+            Flags::COPY_ALPHA,
+        );
+
+        let Ok(transform) = transform else {
+            // A profile for some other color space can't describe these pixels.
+            return self.convert_to_rgb();
+        };
+
+        let pixels = self.bytes.len() / input.bytes_per_pixel();
+        let mut out = vec![0; pixels * output.bytes_per_pixel()];
+        transform.transform_pixels(&self.bytes, &mut out);
+
+        self.bytes = out;
+        self.color_type = color_type;
+    }
+
+    /// Re-encodes YCbCr and CMYK buffers as plain 8-bit RGB. Other layouts are untouched.
+    pub fn convert_to_rgb(&mut self) {
+        if !matches!(self.color_type, ColorType::YCbCr | ColorType::Cmyk) {
+            return;
+        }
+
+        self.bytes = self
+            .rgba_pixels()
+            .iter()
+            .flat_map(|px| [px.r, px.g, px.b])
+            .collect();
+        self.color_type = ColorType::Rgb;
+        self.bit_depth = 8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Metadata;
+
+    #[test]
+    fn sixteen_bit_sources_stay_sixteen_bit() {
+        let samples: [u16; 6] = [0x1234, 0x8001, 0xFEDC, 0x0101, 0x7F7F, 0xABCD];
+
+        let mut image = Decoded {
+            bytes: samples.iter().flat_map(|s| s.to_ne_bytes()).collect(),
+            color_type: ColorType::Rgb,
+            width: 2,
+            height: 1,
+            bit_depth: 16,
+            metadata: Metadata {
+                icc_profile: Some(Profile::new_srgb().icc().unwrap()),
+                ..Metadata::default()
+            },
+        };
+
+        image.convert_to_srgb();
+
+        assert_eq!(image.bit_depth, 16);
+        assert_eq!(image.color_type, ColorType::Rgb);
+        assert_eq!(image.metadata.icc_profile, None);
+
+        // sRGB to sRGB, so only rounding should move the samples, and far less than the
+        // 257 apart that 8-bit samples are.
+        for (converted, original) in image.bytes.chunks_exact(2).zip(samples) {
+            let converted = u16::from_ne_bytes([converted[0], converted[1]]);
+
+            assert!(
+                converted.abs_diff(original) < 32,
+                "{converted:#06x} isn't {original:#06x}"
+            );
+        }
+    }
+}
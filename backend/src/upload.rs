@@ -0,0 +1,177 @@
+use std::{
+    io::{self, Seek, Write},
+    path::Path,
+};
+
+use actix_multipart::{
+    form::{tempfile::TempFile, MultipartFormConfig},
+    MultipartError,
+};
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    error::{InternalError, PayloadError},
+    http::header::CONTENT_LENGTH,
+    web, HttpMessage, HttpRequest, HttpResponse,
+};
+use futures_util::StreamExt;
+use serde::Serialize;
+use tempfile::NamedTempFile;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("Upload has more than {0} multipart parts")]
+struct TooManyParts(usize);
+
+#[derive(Debug, Serialize)]
+struct FormTooLarge {
+    error: String,
+    /// Configured limit on the form's non-file fields, in bytes.
+    limit: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct PayloadTooLarge {
+    error: String,
+    /// Configured upload limit, in bytes.
+    limit: usize,
+    /// Size of the rejected request body in bytes, when the client declared it.
+    received: Option<u64>,
+}
+
+/// Multipart settings shared by every upload endpoint, rejecting bodies over `limit`
+/// bytes with a descriptive 413 instead of actix's generic error. Non-file fields, which
+/// are buffered in memory, may total `memory_limit` bytes before a 400.
+pub fn multipart_config(limit: usize, memory_limit: usize) -> MultipartFormConfig {
+    MultipartFormConfig::default()
+        .total_limit(limit)
+        .memory_limit(memory_limit)
+        .error_handler(move |err, req| match err {
+            // actix reports every exhausted limit the same way, but a body that fits the
+            // total can only have overrun the in-memory one.
+            MultipartError::Payload(PayloadError::Overflow)
+                if content_length(req).is_some_and(|len| len <= limit as u64) =>
+            {
+                let response = HttpResponse::BadRequest().json(FormTooLarge {
+                    error: format!("Form fields exceed the {memory_limit} byte limit"),
+                    limit: memory_limit,
+                });
+
+                InternalError::from_response(err, response).into()
+            }
+            MultipartError::Payload(PayloadError::Overflow) => {
+                InternalError::from_response(err, too_large(limit, req)).into()
+            }
+            MultipartError::Payload(PayloadError::Io(ref e))
+                if e.get_ref().is_some_and(|e| e.is::<TooManyParts>()) =>
+            {
+                let response = HttpResponse::BadRequest().body(e.to_string());
+
+                InternalError::from_response(err, response).into()
+            }
+            err => err.into(),
+        })
+}
+
+/// Caps multipart request bodies at `max_parts` parts, so a client can't make us juggle
+/// thousands of tiny fields or temp files. Delimiters are counted as the body streams in,
+/// and the extractor fails with a 400 as soon as it reads past the cap.
+pub fn limit_parts(req: &mut ServiceRequest, max_parts: usize) {
+    let Some(boundary) = req
+        .mime_type()
+        .ok()
+        .flatten()
+        .filter(|ty| ty.type_() == mime::MULTIPART)
+        .and_then(|ty| ty.get_param(mime::BOUNDARY).map(|b| b.as_str().to_owned()))
+    else {
+        return;
+    };
+
+    let delimiter = format!("--{boundary}").into_bytes();
+    // The end of the previous chunk, in case a delimiter straddles two chunks.
+    let mut tail = Vec::new();
+    let mut delimiters = 0;
+
+    let payload = req.take_payload().map(move |chunk| {
+        let chunk = chunk?;
+
+        let mut window = std::mem::take(&mut tail);
+        window.extend_from_slice(&chunk);
+
+        delimiters += window
+            .windows(delimiter.len())
+            .filter(|w| *w == delimiter)
+            .count();
+        tail = window[window.len().saturating_sub(delimiter.len() - 1)..].to_vec();
+
+        // Each part follows a delimiter, and one more closes the body.
+        match delimiters > max_parts + 1 {
+            true => Err(PayloadError::Io(io::Error::other(TooManyParts(max_parts)))),
+            false => Ok(chunk),
+        }
+    });
+
+    req.set_payload(Payload::Stream {
+        payload: Box::pin(payload),
+    });
+}
+
+/// Streams a raw (non-multipart) request body into a temp file under `dir`, typed by the
+/// request's `Content-Type`, as if it were a multipart file field. Bodies over `limit`
+/// bytes get the same 413 as multipart uploads.
+pub async fn raw_body(
+    req: &HttpRequest,
+    mut payload: web::Payload,
+    limit: usize,
+    dir: &Path,
+) -> actix_web::Result<TempFile> {
+    if content_length(req).is_some_and(|len| len > limit as u64) {
+        return Err(
+            InternalError::from_response(PayloadError::Overflow, too_large(limit, req)).into(),
+        );
+    }
+
+    let mut file = NamedTempFile::new_in(dir)?;
+    let mut size = 0;
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        size += chunk.len();
+
+        // Content-Length may be absent or wrong, so count what actually arrives too.
+        if size > limit {
+            return Err(InternalError::from_response(
+                PayloadError::Overflow,
+                too_large(limit, req),
+            )
+            .into());
+        }
+
+        file.write_all(&chunk)?;
+    }
+
+    file.rewind()?;
+
+    Ok(TempFile {
+        file,
+        content_type: req.mime_type().ok().flatten(),
+        file_name: None,
+        size,
+    })
+}
+
+fn too_large(limit: usize, req: &HttpRequest) -> HttpResponse {
+    HttpResponse::PayloadTooLarge().json(PayloadTooLarge {
+        error: format!("Upload exceeds the {limit} byte limit"),
+        limit,
+        received: content_length(req),
+    })
+}
+
+fn content_length(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}